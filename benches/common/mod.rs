@@ -0,0 +1,64 @@
+use trident_syscall_stubs_v2::with_invoke_context;
+
+use solana_sdk::account::AccountSharedData;
+use solana_sdk::account::WritableAccount;
+use solana_sdk::native_loader;
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::transaction_context::IndexOfAccount;
+use solana_sdk::transaction_context::InstructionAccount;
+use solana_sdk::transaction_context::TransactionAccount;
+
+use solana_program_runtime::invoke_context::BuiltinFunctionWithContext;
+use solana_program_runtime::loaded_programs::ProgramCacheEntry;
+use solana_program_runtime::with_mock_invoke_context;
+
+/// Program the benchmarks execute as.
+pub const CALLER_PROGRAM_ID: Pubkey = Pubkey::new_from_array([1; 32]);
+
+fn builtin_program_account() -> AccountSharedData {
+    let mut account = AccountSharedData::new(1, 0, &native_loader::id());
+    account.set_executable(true);
+    account
+}
+
+/// Runs `f` as if `CALLER_PROGRAM_ID` was executing in a mock invoke context with
+/// `accounts` as its writable signer accounts, with `builtins` available to CPIs.
+pub fn with_program<R>(
+    accounts: Vec<TransactionAccount>,
+    builtins: &[(Pubkey, BuiltinFunctionWithContext)],
+    f: impl FnOnce() -> R,
+) -> R {
+    let instruction_accounts = (0..accounts.len() as IndexOfAccount)
+        .map(|index| InstructionAccount {
+            index_in_transaction: index,
+            index_in_caller: index,
+            index_in_callee: index,
+            is_signer: true,
+            is_writable: true,
+        })
+        .collect::<Vec<_>>();
+    let mut transaction_accounts = accounts;
+    for (program_id, _) in builtins {
+        transaction_accounts.push((*program_id, builtin_program_account()));
+    }
+    let caller_index = transaction_accounts.len() as IndexOfAccount;
+    transaction_accounts.push((CALLER_PROGRAM_ID, builtin_program_account()));
+
+    with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+    let mut program_cache_for_tx_batch = ProgramCacheForTxBatch::default();
+    for (program_id, builtin) in builtins {
+        program_cache_for_tx_batch.replenish(
+            *program_id,
+            Arc::new(ProgramCacheEntry::new_builtin(0, 0, *builtin)),
+        );
+    }
+    invoke_context.program_cache_for_tx_batch = &mut program_cache_for_tx_batch;
+    invoke_context
+        .transaction_context
+        .get_next_instruction_context()
+        .unwrap()
+        .configure(&[caller_index], &instruction_accounts, &[]);
+    invoke_context.push().unwrap();
+
+    with_invoke_context(&mut invoke_context, f)
+}
@@ -0,0 +1,144 @@
+//! Measures `sol_invoke_signed` with the largest instruction data a CPI may carry, next to
+//! the cost of the instruction clone it used to make, and with 64 accounts, next to the
+//! cost of the linear AccountInfo searches it used to make.
+//!
+//! Run with `cargo bench --bench cpi`.
+
+mod common;
+
+use trident_syscall_stubs_v2::set_compute_unit_limit;
+use trident_syscall_stubs_v2::TridentSyscallStubs;
+
+use std::hint::black_box;
+use std::time::Duration;
+use std::time::Instant;
+
+use solana_sdk::account::AccountSharedData;
+use solana_sdk::account_info::AccountInfo;
+use solana_sdk::instruction::AccountMeta;
+use solana_sdk::instruction::Instruction;
+use solana_sdk::program_stubs::SyscallStubs;
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::stable_layout::stable_instruction::StableInstruction;
+
+use solana_program_runtime::declare_process_instruction;
+
+const CALLEE_ID: Pubkey = Pubkey::new_from_array([2; 32]);
+const DATA_LEN: usize = 10 * 1024;
+const NUM_ACCOUNTS: usize = 64;
+/// CPIs per invoke context, below the instruction trace limit.
+const CPIS_PER_CONTEXT: u32 = 50;
+const CONTEXTS: u32 = 200;
+const ITERATIONS: u32 = CONTEXTS * CPIS_PER_CONTEXT;
+
+declare_process_instruction!(Noop, 1, |_invoke_context| { Ok(()) });
+
+/// Returns the time per CPI of invoking `instruction` with `account_infos`, in invoke
+/// contexts with `accounts` as the caller's accounts.
+fn time_cpi(
+    accounts: &[(Pubkey, AccountSharedData)],
+    instruction: &Instruction,
+    account_infos: &[AccountInfo],
+) -> Duration {
+    let stubs = TridentSyscallStubs::default();
+    let mut cpi_time = Duration::ZERO;
+    for _ in 0..CONTEXTS {
+        common::with_program(accounts.to_vec(), &[(CALLEE_ID, Noop::vm)], || {
+            set_compute_unit_limit(u64::MAX);
+            let start = Instant::now();
+            for _ in 0..CPIS_PER_CONTEXT {
+                stubs
+                    .sol_invoke_signed(black_box(instruction), account_infos, &[])
+                    .unwrap();
+            }
+            cpi_time += start.elapsed();
+        });
+    }
+    cpi_time / ITERATIONS
+}
+
+fn bench_instruction_data() {
+    let instruction = Instruction::new_with_bytes(CALLEE_ID, &[1; DATA_LEN], Vec::new());
+    let cpi_time = time_cpi(&[], &instruction, &[]);
+
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        black_box(StableInstruction::from(black_box(&instruction).clone()));
+    }
+    let clone_time = start.elapsed();
+
+    println!(
+        "sol_invoke_signed with {} bytes of data: {:?} per CPI",
+        DATA_LEN, cpi_time
+    );
+    println!(
+        "StableInstruction clone avoided per CPI: {:?}",
+        clone_time / ITERATIONS
+    );
+}
+
+fn bench_accounts() {
+    let accounts = (0..NUM_ACCOUNTS)
+        .map(|_| {
+            let account = AccountSharedData::new(1, 0, &common::CALLER_PROGRAM_ID);
+            (Pubkey::new_unique(), account)
+        })
+        .collect::<Vec<_>>();
+    let instruction = Instruction::new_with_bytes(
+        CALLEE_ID,
+        &[],
+        accounts
+            .iter()
+            .map(|(pubkey, _)| AccountMeta::new(*pubkey, false))
+            .collect(),
+    );
+    let mut lamports = vec![1; NUM_ACCOUNTS];
+    let mut data = vec![Vec::new(); NUM_ACCOUNTS];
+    let account_infos = accounts
+        .iter()
+        .zip(lamports.iter_mut())
+        .zip(data.iter_mut())
+        .map(|(((pubkey, _), lamports), data)| {
+            AccountInfo::new(
+                pubkey,
+                false,
+                true,
+                lamports,
+                data,
+                &common::CALLER_PROGRAM_ID,
+                false,
+                0,
+            )
+        })
+        .collect::<Vec<_>>();
+    let cpi_time = time_cpi(&accounts, &instruction, &account_infos);
+
+    // One search per account in the validation pass and another in copy-in
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        for _ in 0..2 {
+            for account_meta in &black_box(&instruction).accounts {
+                black_box(
+                    account_infos
+                        .iter()
+                        .position(|account_info| *account_info.key == account_meta.pubkey),
+                );
+            }
+        }
+    }
+    let search_time = start.elapsed();
+
+    println!(
+        "sol_invoke_signed with {} accounts: {:?} per CPI",
+        NUM_ACCOUNTS, cpi_time
+    );
+    println!(
+        "Linear AccountInfo searches avoided per CPI: {:?}",
+        search_time / ITERATIONS
+    );
+}
+
+fn main() {
+    bench_instruction_data();
+    bench_accounts();
+}
@@ -0,0 +1,50 @@
+//! Measures a program reading the Clock 10,000 times, which decodes it from the sysvar
+//! cache once and serves the cached copy afterwards.
+//!
+//! Run with `cargo bench --bench sysvars`.
+
+mod common;
+
+use trident_syscall_stubs_v2::set_compute_unit_limit;
+use trident_syscall_stubs_v2::set_stubs_v2;
+
+use std::hint::black_box;
+use std::time::Instant;
+
+use solana_sdk::account::create_account_shared_data_for_test;
+use solana_sdk::clock::Clock;
+use solana_sdk::sysvar::Sysvar;
+use solana_sdk::sysvar::SysvarId;
+
+const READS: u32 = 10_000;
+
+fn main() {
+    set_stubs_v2();
+    let clock = Clock {
+        slot: 7,
+        ..Clock::default()
+    };
+    let accounts = vec![(Clock::id(), create_account_shared_data_for_test(&clock))];
+
+    common::with_program(accounts, &[], || {
+        set_compute_unit_limit(u64::MAX);
+
+        let start = Instant::now();
+        black_box(Clock::get().unwrap());
+        let first_read = start.elapsed();
+
+        let start = Instant::now();
+        for _ in 0..READS {
+            black_box(Clock::get().unwrap());
+        }
+        let reads = start.elapsed();
+
+        println!("First Clock::get: {:?}", first_read);
+        println!(
+            "{} cached Clock::get: {:?} in total, {:?} per read",
+            READS,
+            reads,
+            reads / READS
+        );
+    });
+}
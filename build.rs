@@ -0,0 +1,37 @@
+use std::path::Path;
+
+include!("src/cargo_lock.rs");
+
+/// Returns the path and contents of the Cargo.lock of the workspace being built, looked up
+/// from the build's output directory and this package's directory.
+fn find_cargo_lock() -> Option<(String, String)> {
+    ["OUT_DIR", "CARGO_MANIFEST_DIR"]
+        .into_iter()
+        .filter_map(|var| std::env::var(var).ok())
+        .find_map(|dir| {
+            Path::new(&dir).ancestors().find_map(|dir| {
+                let path = dir.join("Cargo.lock");
+                let lock = std::fs::read_to_string(&path).ok()?;
+                Some((path.display().to_string(), lock))
+            })
+        })
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=src/cargo_lock.rs");
+    let Some((path, lock)) = find_cargo_lock() else {
+        return;
+    };
+    println!("cargo:rerun-if-changed={}", path);
+
+    let package = std::env::var("CARGO_PKG_NAME").unwrap();
+    let Some(sdk_version) = resolved_version(&lock, &package, "solana-sdk") else {
+        return;
+    };
+    println!("cargo:rustc-env=TRIDENT_SOLANA_SDK_VERSION={}", sdk_version);
+    if let Some(mismatch) = sdk_version_mismatch(&lock, sdk_version) {
+        println!("cargo:warning={}", mismatch);
+        println!("cargo:rustc-env=TRIDENT_SDK_VERSION_MISMATCH={}", mismatch);
+    }
+}
@@ -0,0 +1,110 @@
+use crate::get_invoke_context;
+use crate::logs::peek_program_logs;
+
+use solana_sdk::pubkey::Pubkey;
+
+/// Panics unless a program log of the current invoke context contains `pattern`.
+#[track_caller]
+pub fn assert_logs_contain(pattern: &str) {
+    let logs = peek_program_logs();
+    if !logs.iter().any(|log| log.contains(pattern)) {
+        panic!(
+            "no program log contains {:?}\nlogs:\n{}",
+            pattern,
+            logs.join("\n")
+        );
+    }
+}
+
+/// Panics unless the program logs of the current invoke context contain each of `patterns`
+/// in order, not necessarily in consecutive logs.
+#[track_caller]
+pub fn assert_log_sequence(patterns: &[&str]) {
+    let logs = peek_program_logs();
+    let mut remaining = logs.iter();
+    for pattern in patterns {
+        if !remaining.any(|log| log.contains(pattern)) {
+            panic!(
+                "no program log contains {:?} after the preceding patterns {:?}\nlogs:\n{}",
+                pattern,
+                patterns,
+                logs.join("\n")
+            );
+        }
+    }
+}
+
+/// Panics unless the current transaction's return data was set by `program_id` to
+/// `expected`.
+#[track_caller]
+pub fn assert_return_data(program_id: &Pubkey, expected: &[u8]) {
+    let (actual_program_id, actual) = get_invoke_context().transaction_context.get_return_data();
+    if actual_program_id != program_id || actual != expected {
+        panic!(
+            "expected return data {:?} from {}, got {:?} from {}\nlogs:\n{}",
+            expected,
+            program_id,
+            actual,
+            actual_program_id,
+            peek_program_logs().join("\n")
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::with_mock_caller;
+    use crate::test_utils::CALLER_PROGRAM_ID;
+    use crate::TridentSyscallStubs;
+
+    use solana_sdk::program_stubs::SyscallStubs;
+
+    /// Runs `f` in an invoke context whose program logged "first" and "second" and set the
+    /// return data `[1, 2]`.
+    fn with_logs_and_return_data(f: impl FnOnce()) {
+        with_mock_caller(Vec::new(), &[], || {
+            let stubs = TridentSyscallStubs::default();
+            stubs.sol_log("first");
+            stubs.sol_log("second");
+            get_invoke_context()
+                .transaction_context
+                .set_return_data(CALLER_PROGRAM_ID, vec![1, 2])
+                .unwrap();
+            f();
+        });
+    }
+
+    #[test]
+    fn assertions_pass_on_matching_logs_and_return_data() {
+        with_logs_and_return_data(|| {
+            assert_logs_contain("Program log: sec");
+            assert_log_sequence(&["first", "second"]);
+            assert_return_data(&CALLER_PROGRAM_ID, &[1, 2]);
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "no program log contains \"third\"")]
+    fn assert_logs_contain_panics_without_a_match() {
+        with_logs_and_return_data(|| assert_logs_contain("third"));
+    }
+
+    #[test]
+    #[should_panic(expected = "no program log contains \"first\" after the preceding patterns")]
+    fn assert_log_sequence_panics_on_the_wrong_order() {
+        with_logs_and_return_data(|| assert_log_sequence(&["second", "first"]));
+    }
+
+    #[test]
+    #[should_panic(expected = "expected return data [1, 3]")]
+    fn assert_return_data_panics_on_other_data() {
+        with_logs_and_return_data(|| assert_return_data(&CALLER_PROGRAM_ID, &[1, 3]));
+    }
+
+    #[test]
+    #[should_panic(expected = "expected return data [1, 2] from 11111111111111111111111111111111")]
+    fn assert_return_data_panics_on_another_program() {
+        with_logs_and_return_data(|| assert_return_data(&Pubkey::default(), &[1, 2]));
+    }
+}
@@ -0,0 +1,138 @@
+// Shared with build.rs, which includes this file to resolve the solana-sdk version.
+
+/// A package entry of a Cargo.lock.
+struct LockedPackage<'a> {
+    name: &'a str,
+    version: &'a str,
+    /// Dependency names, followed by their version where several versions are locked.
+    dependencies: Vec<&'a str>,
+}
+
+fn parse_cargo_lock(lock: &str) -> Vec<LockedPackage<'_>> {
+    let mut packages = Vec::new();
+    let mut in_dependencies = false;
+    for line in lock.lines().map(str::trim) {
+        if line == "[[package]]" {
+            packages.push(LockedPackage {
+                name: "",
+                version: "",
+                dependencies: Vec::new(),
+            });
+            in_dependencies = false;
+            continue;
+        }
+        let Some(package) = packages.last_mut() else {
+            continue;
+        };
+        if in_dependencies {
+            match line.strip_suffix(',').unwrap_or(line).trim_matches('"') {
+                "]" => in_dependencies = false,
+                dependency => package.dependencies.push(dependency),
+            }
+        } else if let Some(name) = line.strip_prefix("name = ") {
+            package.name = name.trim_matches('"');
+        } else if let Some(version) = line.strip_prefix("version = ") {
+            package.version = version.trim_matches('"');
+        } else if line == "dependencies = [" {
+            in_dependencies = true;
+        }
+    }
+    packages
+}
+
+/// Returns the version of `dependency` that `dependent` is built against, as locked in
+/// `lock`.
+fn resolved_version<'a>(lock: &'a str, dependent: &str, dependency: &str) -> Option<&'a str> {
+    let packages = parse_cargo_lock(lock);
+    let entry = packages
+        .iter()
+        .find(|package| package.name == dependent)?
+        .dependencies
+        .iter()
+        .find(|entry| entry.split(' ').next() == Some(dependency))?;
+    match entry.split(' ').nth(1) {
+        Some(version) => Some(version),
+        // Only one version is locked
+        None => packages
+            .iter()
+            .find(|package| package.name == dependency)
+            .map(|package| package.version),
+    }
+}
+
+/// Describes the solana-program versions locked in `lock` besides `sdk_version`, which the
+/// stubs are built against, as a program built against one of them cannot use the stubs.
+fn sdk_version_mismatch(lock: &str, sdk_version: &str) -> Option<String> {
+    let other_versions = parse_cargo_lock(lock)
+        .into_iter()
+        .filter(|package| package.name == "solana-program" && package.version != sdk_version)
+        .map(|package| package.version)
+        .collect::<Vec<_>>();
+    if other_versions.is_empty() {
+        return None;
+    }
+    Some(format!(
+        "solana-program {} is locked besides solana-sdk {}, which trident-syscall-stubs-v2 \
+         is built against; programs built against solana-program {} cannot use the stubs",
+        other_versions.join(", "),
+        sdk_version,
+        other_versions.join(", "),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cargo_lock(solana_program_versions: &[&str]) -> String {
+        let mut lock = String::from(
+            "version = 3\n\
+             \n\
+             [[package]]\n\
+             name = \"solana-sdk\"\n\
+             version = \"2.0.21\"\n\
+             dependencies = [\n \"solana-program 2.0.21\",\n]\n\
+             \n\
+             [[package]]\n\
+             name = \"trident-syscall-stubs-v2\"\n\
+             version = \"0.0.1\"\n\
+             dependencies = [\n \"log\",\n \"solana-sdk\",\n]\n",
+        );
+        for version in solana_program_versions {
+            lock.push_str(&format!(
+                "\n[[package]]\nname = \"solana-program\"\nversion = \"{}\"\n\
+                 source = \"registry+https://github.com/rust-lang/crates.io-index\"\n",
+                version
+            ));
+        }
+        lock
+    }
+
+    #[test]
+    fn resolves_the_locked_solana_sdk_version() {
+        let lock = cargo_lock(&["2.0.21"]);
+        assert_eq!(
+            resolved_version(&lock, "trident-syscall-stubs-v2", "solana-sdk"),
+            Some("2.0.21")
+        );
+        assert_eq!(
+            resolved_version(&lock, "solana-sdk", "solana-program"),
+            Some("2.0.21")
+        );
+        assert_eq!(resolved_version(&lock, "solana-sdk", "log"), None);
+        assert_eq!(sdk_version_mismatch(&lock, "2.0.21"), None);
+    }
+
+    #[test]
+    fn reports_both_versions_on_a_mismatch() {
+        let lock = cargo_lock(&["1.18.26", "2.0.21"]);
+        assert_eq!(
+            sdk_version_mismatch(&lock, "2.0.21").as_deref(),
+            Some(
+                "solana-program 1.18.26 is locked besides solana-sdk 2.0.21, which \
+                 trident-syscall-stubs-v2 is built against; programs built against \
+                 solana-program 1.18.26 cannot use the stubs"
+            )
+        );
+    }
+}
@@ -0,0 +1,75 @@
+use crate::get_invoke_context;
+use crate::invoke_context::with_context_state;
+use crate::try_get_invoke_context;
+
+use solana_program_runtime::solana_rbpf::vm::ContextObject;
+
+/// Limits the compute units available to the current invoke context, or to the next one
+/// installed if none is. Once metered costs such as CPIs, memory and sysvar syscalls exceed
+/// the limit, the instruction fails with `ComputationalBudgetExceeded`.
+pub fn set_compute_unit_limit(limit: u64) {
+    if let Some(invoke_context) = try_get_invoke_context() {
+        invoke_context.mock_set_remaining(limit);
+    }
+    with_context_state(|state| state.compute_unit_limit = Some(limit));
+}
+
+/// Returns the compute units left in the current invoke context.
+pub fn get_remaining_compute_units() -> u64 {
+    get_invoke_context().get_remaining()
+}
+
+/// Returns the compute units consumed in the current invoke context, measured against the
+/// limit set with `set_compute_unit_limit`, or the compute budget's limit otherwise.
+pub fn get_compute_units_consumed() -> u64 {
+    let invoke_context = get_invoke_context();
+    let limit = with_context_state(|state| state.compute_unit_limit)
+        .unwrap_or(invoke_context.get_compute_budget().compute_unit_limit);
+    limit.saturating_sub(invoke_context.get_remaining())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::take_last_cpi_error;
+    use crate::test_utils::with_mock_caller;
+    use crate::TridentStubError;
+    use crate::TridentSyscallStubs;
+
+    use solana_sdk::entrypoint::ProgramResult;
+    use solana_sdk::instruction::Instruction;
+    use solana_sdk::instruction::InstructionError;
+    use solana_sdk::program_stubs::SyscallStubs;
+    use solana_sdk::pubkey::Pubkey;
+
+    use solana_program_runtime::declare_process_instruction;
+
+    const CALLEE_ID: Pubkey = Pubkey::new_from_array([2; 32]);
+
+    declare_process_instruction!(Noop, 1, |_invoke_context| { Ok(()) });
+
+    /// Program making ten CPIs, which cost more than 10,000 compute units.
+    fn program() -> ProgramResult {
+        let instruction = Instruction::new_with_bytes(CALLEE_ID, &[], Vec::new());
+        for _ in 0..10 {
+            TridentSyscallStubs::default().sol_invoke_signed(&instruction, &[], &[])?;
+        }
+        Ok(())
+    }
+
+    fn run_program(compute_unit_limit: u64) -> Result<(), TridentStubError> {
+        set_compute_unit_limit(compute_unit_limit);
+        with_mock_caller(Vec::new(), &[(CALLEE_ID, Noop::vm)], || {
+            program().map_err(|error| take_last_cpi_error().unwrap_or(error.into()))
+        })
+    }
+
+    #[test]
+    fn programs_fail_once_the_compute_unit_limit_is_exceeded() {
+        assert_eq!(run_program(1_400_000), Ok(()));
+        assert_eq!(
+            run_program(10_000).map_err(InstructionError::from),
+            Err(InstructionError::ComputationalBudgetExceeded)
+        );
+    }
+}
@@ -0,0 +1,35 @@
+use crate::error::TridentStubError;
+use crate::invoke_context::with_context_state;
+
+use solana_sdk::instruction::AccountMeta;
+use solana_sdk::pubkey::Pubkey;
+
+/// A cross-program invocation made through `sol_invoke_signed`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CpiRecord {
+    pub program_id: Pubkey,
+    pub accounts: Vec<AccountMeta>,
+    pub data: Vec<u8>,
+    /// Result of the CPI before its conversion into the `ProgramError` the caller received.
+    pub result: Result<(), TridentStubError>,
+    /// Compute units consumed by the CPI, including its nested CPIs.
+    pub compute_units_consumed: u64,
+    /// Stack height of the callee, so nested CPIs can be told apart from their callers.
+    pub stack_height: usize,
+}
+
+/// Returns the CPIs made within the current invoke context since the last call, each one
+/// followed by the CPIs it made in turn.
+pub fn take_cpi_trace() -> Vec<CpiRecord> {
+    with_context_state(|state| std::mem::take(&mut state.cpi_trace))
+}
+
+/// Returns the error of the most recent failed CPI within the current invoke context, if not
+/// taken yet.
+///
+/// Unlike the `ProgramError` the caller received, the error keeps `InstructionError`s that
+/// have no `ProgramError` counterpart, which `sol_invoke_signed` reports as
+/// `InvalidArgument`.
+pub fn take_last_cpi_error() -> Option<TridentStubError> {
+    with_context_state(|state| state.last_cpi_error.take())
+}
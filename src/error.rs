@@ -0,0 +1,55 @@
+use crate::instruction_error_to_program_error;
+use crate::program_error_to_instruction_error;
+
+use solana_sdk::instruction::InstructionError;
+use solana_sdk::program_error::ProgramError;
+use solana_sdk::pubkey::Pubkey;
+
+use thiserror::Error;
+
+/// Failure inside the stubs. Separates a misconfigured harness or an invalid request by
+/// the caller from the failure of the program it invoked.
+#[derive(Clone, Debug, Error, PartialEq, Eq)]
+pub enum TridentStubError {
+    #[error("syscall stubs called outside of program execution")]
+    MissingInvokeContext,
+    #[error("instruction references an unknown account {pubkey}")]
+    AccountNotProvided { pubkey: Pubkey },
+    #[error("account {pubkey} is already borrowed by the caller")]
+    AccountBorrowed { pubkey: Pubkey },
+    #[error("sysvar {id} is not available")]
+    SysvarUnavailable { id: Pubkey },
+    /// The runtime rejected the CPI before the callee ran.
+    #[error("invalid cross-program invocation: {0}")]
+    InvalidInvocation(InstructionError),
+    #[error("callee failed: {0}")]
+    CalleeFailed(InstructionError),
+    #[error(transparent)]
+    Program(#[from] ProgramError),
+}
+
+impl From<TridentStubError> for InstructionError {
+    fn from(error: TridentStubError) -> Self {
+        match error {
+            TridentStubError::MissingInvokeContext => {
+                InstructionError::ProgramEnvironmentSetupFailure
+            }
+            TridentStubError::AccountNotProvided { .. } => InstructionError::MissingAccount,
+            TridentStubError::AccountBorrowed { .. } => InstructionError::AccountBorrowFailed,
+            TridentStubError::SysvarUnavailable { .. } => InstructionError::UnsupportedSysvar,
+            TridentStubError::InvalidInvocation(error) | TridentStubError::CalleeFailed(error) => {
+                error
+            }
+            TridentStubError::Program(error) => program_error_to_instruction_error(&error),
+        }
+    }
+}
+
+impl From<TridentStubError> for ProgramError {
+    fn from(error: TridentStubError) -> Self {
+        match error {
+            TridentStubError::Program(error) => error,
+            error => instruction_error_to_program_error(error.into()),
+        }
+    }
+}
@@ -0,0 +1,179 @@
+use crate::cpi_trace::CpiRecord;
+use crate::fault_injection::injected_fault_count;
+use crate::fault_injection::injected_faults_since;
+use crate::fault_injection::InjectedFault;
+use crate::get_invoke_context;
+use crate::invoke_context::with_context_state;
+
+use std::cell::RefCell;
+
+use solana_sdk::account::AccountSharedData;
+use solana_sdk::instruction::InstructionError;
+use solana_sdk::pubkey::Pubkey;
+
+use solana_program_runtime::solana_rbpf::vm::ContextObject;
+
+/// Summary of a top-level instruction, collected between `begin_execution_report` and
+/// `end_execution_report`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExecutionReport {
+    pub program_id: Pubkey,
+    pub result: Result<(), InstructionError>,
+    /// Compute units consumed by the instruction, including its CPIs.
+    pub compute_units_consumed: u64,
+    /// Number of CPIs made at any depth.
+    pub cpi_count: usize,
+    pub return_data: Option<(Pubkey, Vec<u8>)>,
+    pub log_lines: usize,
+    /// Faults injected into the instruction's syscalls, in order.
+    pub injected_faults: Vec<InjectedFault>,
+    /// The CPIs made at any depth, as in the CPI trace, unless the trace was taken while the
+    /// instruction was executing.
+    pub cpis: Vec<CpiRecord>,
+    /// The instruction's logs, unless they were taken while it was executing.
+    pub logs: Vec<String>,
+    /// The instruction's accounts it changed.
+    pub account_changes: Vec<AccountChange>,
+}
+
+/// An account of an instruction before and after it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AccountChange {
+    pub pubkey: Pubkey,
+    pub before: AccountSharedData,
+    pub after: AccountSharedData,
+}
+
+pub(crate) struct ReportStart {
+    program_id: Pubkey,
+    compute_units_available: u64,
+    cpi_trace_len: usize,
+    log_lines: usize,
+    injected_faults: usize,
+    accounts: Vec<(Pubkey, AccountSharedData)>,
+}
+
+thread_local! {
+    static LAST_REPORT: RefCell<Option<ExecutionReport>> = const { RefCell::new(None) };
+}
+
+/// Starts collecting an `ExecutionReport` for an instruction of `program_id` executed in the
+/// current invoke context.
+pub fn begin_execution_report(program_id: &Pubkey) {
+    let invoke_context = get_invoke_context();
+    let start = ReportStart {
+        program_id: *program_id,
+        compute_units_available: invoke_context.get_remaining(),
+        cpi_trace_len: with_context_state(|state| state.cpi_trace.len()),
+        log_lines: log_lines(),
+        injected_faults: injected_fault_count(),
+        accounts: instruction_accounts(),
+    };
+    with_context_state(|state| state.report_start = Some(start));
+}
+
+/// Finishes the report started by `begin_execution_report` with the instruction's `result`.
+/// Call it on every exit path, including after catching a panic, so the report is
+/// available through `take_last_report`.
+pub fn end_execution_report(result: Result<(), InstructionError>) {
+    let Some(start) = with_context_state(|state| state.report_start.take()) else {
+        return;
+    };
+    let invoke_context = get_invoke_context();
+    let (return_data_program_id, return_data) =
+        invoke_context.transaction_context.get_return_data();
+
+    let accounts_after = instruction_accounts();
+    let report = ExecutionReport {
+        program_id: start.program_id,
+        result,
+        compute_units_consumed: start
+            .compute_units_available
+            .saturating_sub(invoke_context.get_remaining()),
+        cpi_count: with_context_state(|state| state.cpi_trace.len())
+            .saturating_sub(start.cpi_trace_len),
+        cpis: with_context_state(|state| {
+            state
+                .cpi_trace
+                .get(start.cpi_trace_len..)
+                .map(<[CpiRecord]>::to_vec)
+                .unwrap_or_default()
+        }),
+        return_data: (!return_data.is_empty())
+            .then(|| (*return_data_program_id, return_data.to_vec())),
+        log_lines: log_lines().saturating_sub(start.log_lines),
+        injected_faults: injected_faults_since(start.injected_faults),
+        logs: logs_since(start.log_lines),
+        account_changes: start
+            .accounts
+            .into_iter()
+            .filter_map(|(pubkey, before)| {
+                let (_, after) = accounts_after.iter().find(|(key, _)| *key == pubkey)?;
+                (before != *after).then(|| AccountChange {
+                    pubkey,
+                    before,
+                    after: after.clone(),
+                })
+            })
+            .collect(),
+    };
+    LAST_REPORT.with(|last_report| last_report.replace(Some(report)));
+}
+
+/// Returns the report most recently finished on the current thread, if not taken yet. The
+/// report outlives the invoke context it was collected in.
+pub fn take_last_report() -> Option<ExecutionReport> {
+    LAST_REPORT.with(|last_report| last_report.take())
+}
+
+fn log_lines() -> usize {
+    get_invoke_context()
+        .get_log_collector()
+        .map(|log_collector| log_collector.borrow().messages.len())
+        .unwrap_or(0)
+}
+
+fn logs_since(start: usize) -> Vec<String> {
+    get_invoke_context()
+        .get_log_collector()
+        .and_then(|log_collector| {
+            log_collector
+                .borrow()
+                .messages
+                .get(start..)
+                .map(<[String]>::to_vec)
+        })
+        .unwrap_or_default()
+}
+
+/// Returns the keys and contents of the current instruction's accounts, skipping
+/// duplicates and accounts that are borrowed.
+fn instruction_accounts() -> Vec<(Pubkey, AccountSharedData)> {
+    let transaction_context = &get_invoke_context().transaction_context;
+    let Ok(instruction_context) = transaction_context.get_current_instruction_context() else {
+        return Vec::new();
+    };
+    (0..instruction_context.get_number_of_instruction_accounts())
+        .filter(|index_in_instruction| {
+            matches!(
+                instruction_context.is_instruction_account_duplicate(*index_in_instruction),
+                Ok(None)
+            )
+        })
+        .filter_map(|index_in_instruction| {
+            let index_in_transaction = instruction_context
+                .get_index_of_instruction_account_in_transaction(index_in_instruction)
+                .ok()?;
+            let pubkey = transaction_context
+                .get_key_of_account_at_index(index_in_transaction)
+                .ok()?;
+            let account = transaction_context
+                .get_account_at_index(index_in_transaction)
+                .ok()?
+                .try_borrow()
+                .ok()?
+                .clone();
+            Some((*pubkey, account))
+        })
+        .collect()
+}
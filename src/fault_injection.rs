@@ -0,0 +1,232 @@
+use std::cell::RefCell;
+
+use solana_sdk::program_error::ProgramError;
+use solana_sdk::pubkey::Pubkey;
+
+/// Syscall failures the stubs inject before running the real implementation.
+///
+/// Probabilistic faults are decided by a generator seeded with `seed`, so a run replays
+/// the same faults given the same seed and the same sequence of syscalls.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FaultConfig {
+    pub seed: u64,
+    /// Fails every Nth `sol_invoke_signed` with the error, without invoking the callee.
+    pub invoke_every_nth: Option<(u64, ProgramError)>,
+    /// Probability of a sysvar getter reporting the sysvar as unavailable.
+    pub sysvar_failure_probability: f64,
+    /// Probability of `sol_get_return_data` reporting no return data.
+    pub return_data_failure_probability: f64,
+}
+
+/// A fault injected into a syscall.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum InjectedFault {
+    Invoke {
+        program_id: Pubkey,
+        error: ProgramError,
+    },
+    Sysvar {
+        sysvar_id: Pubkey,
+    },
+    ReturnData,
+}
+
+struct FaultState {
+    config: FaultConfig,
+    rng: u64,
+    invocations: u64,
+    injected: Vec<InjectedFault>,
+}
+
+impl FaultState {
+    /// Returns `true` with `probability`, using splitmix64.
+    fn roll(&mut self, probability: f64) -> bool {
+        if probability <= 0.0 {
+            return false;
+        }
+        self.rng = self.rng.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.rng;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        ((z >> 11) as f64 / (1u64 << 53) as f64) < probability
+    }
+}
+
+thread_local! {
+    static FAULT_STATE: RefCell<Option<FaultState>> = const { RefCell::new(None) };
+}
+
+/// Starts injecting the faults of `config` into the syscalls made on the current thread,
+/// restarting the generator and the invocation count.
+pub fn set_fault_config(config: FaultConfig) {
+    let state = FaultState {
+        rng: config.seed,
+        config,
+        invocations: 0,
+        injected: Vec::new(),
+    };
+    FAULT_STATE.with(|fault_state| fault_state.replace(Some(state)));
+}
+
+/// Stops injecting faults on the current thread.
+pub fn clear_fault_config() {
+    FAULT_STATE.with(|fault_state| fault_state.replace(None));
+}
+
+/// Returns the faults injected since the last call, in order.
+pub fn take_injected_faults() -> Vec<InjectedFault> {
+    FAULT_STATE.with(|fault_state| {
+        fault_state
+            .borrow_mut()
+            .as_mut()
+            .map(|state| std::mem::take(&mut state.injected))
+            .unwrap_or_default()
+    })
+}
+
+pub(crate) fn injected_fault_count() -> usize {
+    FAULT_STATE.with(|fault_state| {
+        fault_state
+            .borrow()
+            .as_ref()
+            .map_or(0, |state| state.injected.len())
+    })
+}
+
+pub(crate) fn injected_faults_since(start: usize) -> Vec<InjectedFault> {
+    FAULT_STATE.with(|fault_state| {
+        fault_state
+            .borrow()
+            .as_ref()
+            .and_then(|state| state.injected.get(start..))
+            .map(<[InjectedFault]>::to_vec)
+            .unwrap_or_default()
+    })
+}
+
+fn inject(decide: impl FnOnce(&mut FaultState) -> Option<InjectedFault>) -> Option<InjectedFault> {
+    FAULT_STATE.with(|fault_state| {
+        let mut fault_state = fault_state.borrow_mut();
+        let state = fault_state.as_mut()?;
+        let fault = decide(state)?;
+        state.injected.push(fault.clone());
+        Some(fault)
+    })
+}
+
+pub(crate) fn inject_invoke_fault(program_id: &Pubkey) -> Option<ProgramError> {
+    let mut injected_error = None;
+    inject(|state| {
+        let (n, error) = state.config.invoke_every_nth.clone()?;
+        state.invocations = state.invocations.saturating_add(1);
+        if n == 0 || state.invocations % n != 0 {
+            return None;
+        }
+        injected_error = Some(error.clone());
+        Some(InjectedFault::Invoke {
+            program_id: *program_id,
+            error,
+        })
+    });
+    injected_error
+}
+
+pub(crate) fn inject_sysvar_fault(sysvar_id: &Pubkey) -> bool {
+    inject(|state| {
+        state
+            .roll(state.config.sysvar_failure_probability)
+            .then_some(InjectedFault::Sysvar {
+                sysvar_id: *sysvar_id,
+            })
+    })
+    .is_some()
+}
+
+pub(crate) fn inject_return_data_fault() -> bool {
+    inject(|state| {
+        state
+            .roll(state.config.return_data_failure_probability)
+            .then_some(InjectedFault::ReturnData)
+    })
+    .is_some()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sysvar_faults(config: FaultConfig, syscalls: usize) -> Vec<bool> {
+        set_fault_config(config);
+        let faults = (0..syscalls)
+            .map(|_| inject_sysvar_fault(&Pubkey::default()))
+            .collect();
+        clear_fault_config();
+        faults
+    }
+
+    #[test]
+    fn a_seed_replays_the_same_faults() {
+        let config = FaultConfig {
+            seed: 7,
+            sysvar_failure_probability: 0.5,
+            ..FaultConfig::default()
+        };
+        let faults = sysvar_faults(config.clone(), 64);
+        assert!(faults.contains(&true) && faults.contains(&false));
+        assert_eq!(sysvar_faults(config.clone(), 64), faults);
+        assert_ne!(sysvar_faults(FaultConfig { seed: 8, ..config }, 64), faults);
+    }
+
+    #[test]
+    fn invoke_every_nth_fails_every_nth_invocation() {
+        let program_id = Pubkey::new_unique();
+        set_fault_config(FaultConfig {
+            invoke_every_nth: Some((3, ProgramError::InvalidArgument)),
+            ..FaultConfig::default()
+        });
+
+        let errors = (0..6)
+            .map(|_| inject_invoke_fault(&program_id))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            errors,
+            [
+                None,
+                None,
+                Some(ProgramError::InvalidArgument),
+                None,
+                None,
+                Some(ProgramError::InvalidArgument),
+            ]
+        );
+        let fault = InjectedFault::Invoke {
+            program_id,
+            error: ProgramError::InvalidArgument,
+        };
+        assert_eq!(take_injected_faults(), [fault.clone(), fault]);
+        clear_fault_config();
+    }
+
+    #[test]
+    fn probabilities_zero_and_one_never_and_always_fail() {
+        for (probability, fails) in [(0.0, false), (1.0, true)] {
+            let faults = sysvar_faults(
+                FaultConfig {
+                    sysvar_failure_probability: probability,
+                    ..FaultConfig::default()
+                },
+                100,
+            );
+            assert!(faults.iter().all(|fault| *fault == fails));
+
+            set_fault_config(FaultConfig {
+                return_data_failure_probability: probability,
+                ..FaultConfig::default()
+            });
+            assert!((0..100).all(|_| inject_return_data_fault() == fails));
+            assert_eq!(take_injected_faults().len(), if fails { 100 } else { 0 });
+            clear_fault_config();
+        }
+    }
+}
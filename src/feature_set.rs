@@ -0,0 +1,33 @@
+use crate::get_invoke_context;
+use crate::try_get_invoke_context;
+
+use std::sync::Arc;
+
+use solana_sdk::feature_set::FeatureSet;
+use solana_sdk::pubkey::Pubkey;
+
+/// Replaces the feature set of the current invoke context.
+pub fn set_feature_set(feature_set: FeatureSet) {
+    get_invoke_context().mock_set_feature_set(Arc::new(feature_set));
+}
+
+/// Activates `feature_id` in the current invoke context, as of slot 0.
+pub fn activate_feature(feature_id: &Pubkey) {
+    let mut feature_set = get_invoke_context().get_feature_set().clone();
+    feature_set.activate(feature_id, 0);
+    set_feature_set(feature_set);
+}
+
+/// Deactivates `feature_id` in the current invoke context.
+pub fn deactivate_feature(feature_id: &Pubkey) {
+    let mut feature_set = get_invoke_context().get_feature_set().clone();
+    feature_set.deactivate(feature_id);
+    set_feature_set(feature_set);
+}
+
+/// Returns whether `feature_id` is active in the current invoke context, or `false` when
+/// no invoke context is set.
+pub(crate) fn is_feature_active(feature_id: &Pubkey) -> bool {
+    try_get_invoke_context()
+        .is_some_and(|invoke_context| invoke_context.get_feature_set().is_active(feature_id))
+}
@@ -0,0 +1,170 @@
+use crate::get_invoke_context;
+
+use solana_sdk::instruction::InstructionError;
+use solana_sdk::instruction::TRANSACTION_LEVEL_STACK_HEIGHT;
+use solana_sdk::sysvar::instructions;
+use solana_sdk::sysvar::instructions::construct_instructions_data;
+use solana_sdk::sysvar::instructions::store_current_index;
+use solana_sdk::sysvar::instructions::BorrowedAccountMeta;
+use solana_sdk::sysvar::instructions::BorrowedInstruction;
+
+/// Serializes the top-level instructions of the current transaction into the Instructions
+/// sysvar account, with the current index pointing at the instruction being executed.
+///
+/// Only instructions that already started executing are recorded in the transaction
+/// context, so the sysvar lists the preceding instructions and the current one, in
+/// transaction order. Call this before each top-level instruction runs.
+///
+/// Does nothing if the transaction does not load the Instructions sysvar account.
+pub fn update_instructions_sysvar() -> Result<(), InstructionError> {
+    let invoke_context = get_invoke_context();
+    let transaction_context = &invoke_context.transaction_context;
+
+    let Some(index_of_sysvar) = transaction_context.find_index_of_account(&instructions::id())
+    else {
+        return Ok(());
+    };
+
+    let mut borrowed_instructions = Vec::new();
+    for index_in_trace in 0..transaction_context.get_instruction_trace_length() {
+        let instruction_context =
+            transaction_context.get_instruction_context_at_index_in_trace(index_in_trace)?;
+        if instruction_context.get_stack_height() != TRANSACTION_LEVEL_STACK_HEIGHT {
+            continue;
+        }
+
+        let mut accounts =
+            Vec::with_capacity(instruction_context.get_number_of_instruction_accounts() as usize);
+        for index_in_instruction in 0..instruction_context.get_number_of_instruction_accounts() {
+            let index_in_transaction = instruction_context
+                .get_index_of_instruction_account_in_transaction(index_in_instruction)?;
+            accounts.push(BorrowedAccountMeta {
+                pubkey: transaction_context.get_key_of_account_at_index(index_in_transaction)?,
+                is_signer: instruction_context
+                    .is_instruction_account_signer(index_in_instruction)?,
+                is_writable: instruction_context
+                    .is_instruction_account_writable(index_in_instruction)?,
+            });
+        }
+
+        borrowed_instructions.push(BorrowedInstruction {
+            program_id: instruction_context.get_last_program_key(transaction_context)?,
+            accounts,
+            data: instruction_context.get_instruction_data(),
+        });
+    }
+
+    let Some(current_index) = borrowed_instructions.len().checked_sub(1) else {
+        return Ok(());
+    };
+
+    let mut data = construct_instructions_data(&borrowed_instructions);
+    store_current_index(&mut data, current_index as u16);
+
+    transaction_context
+        .get_account_at_index(index_of_sysvar)?
+        .try_borrow_mut()
+        .map_err(|_| InstructionError::AccountBorrowFailed)?
+        .set_data_from_slice(&data);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::with_mock_caller;
+    use crate::test_utils::CALLER_PROGRAM_ID;
+
+    use solana_sdk::account::AccountSharedData;
+    use solana_sdk::account::ReadableAccount;
+    use solana_sdk::account_info::AccountInfo;
+    use solana_sdk::instruction::AccountMeta;
+    use solana_sdk::instruction::Instruction;
+    use solana_sdk::pubkey::Pubkey;
+    use solana_sdk::sysvar;
+    use solana_sdk::sysvar::instructions::load_current_index_checked;
+    use solana_sdk::sysvar::instructions::load_instruction_at_checked;
+    use solana_sdk::transaction_context::InstructionAccount;
+
+    #[test]
+    fn update_instructions_sysvar_serializes_the_executed_instructions() {
+        let pubkey = Pubkey::new_unique();
+        let accounts = vec![
+            (
+                instructions::id(),
+                AccountSharedData::new(1, 0, &sysvar::id()),
+            ),
+            (pubkey, AccountSharedData::new(1, 0, &CALLER_PROGRAM_ID)),
+        ];
+
+        with_mock_caller(accounts, &[], || {
+            // Run a second top-level instruction, passing only `pubkey` as a read-only account
+            let invoke_context = get_invoke_context();
+            invoke_context.pop().unwrap();
+            let transaction_context = &mut invoke_context.transaction_context;
+            let program_index = transaction_context
+                .find_index_of_account(&CALLER_PROGRAM_ID)
+                .unwrap();
+            transaction_context
+                .get_next_instruction_context()
+                .unwrap()
+                .configure(
+                    &[program_index],
+                    &[InstructionAccount {
+                        index_in_transaction: 1,
+                        index_in_caller: 1,
+                        index_in_callee: 0,
+                        is_signer: false,
+                        is_writable: false,
+                    }],
+                    &[7, 8, 9],
+                );
+            invoke_context.push().unwrap();
+
+            update_instructions_sysvar().unwrap();
+
+            let transaction_context = &invoke_context.transaction_context;
+            let mut data = transaction_context
+                .get_account_at_index(0)
+                .unwrap()
+                .borrow()
+                .data()
+                .to_vec();
+            let mut lamports = 1;
+            let key = instructions::id();
+            let owner = sysvar::id();
+            let account_info = AccountInfo::new(
+                &key,
+                false,
+                false,
+                &mut lamports,
+                &mut data,
+                &owner,
+                false,
+                0,
+            );
+
+            assert_eq!(load_current_index_checked(&account_info), Ok(1));
+            assert_eq!(
+                load_instruction_at_checked(0, &account_info),
+                Ok(Instruction::new_with_bytes(
+                    CALLER_PROGRAM_ID,
+                    &[],
+                    vec![
+                        AccountMeta::new(instructions::id(), true),
+                        AccountMeta::new(pubkey, true),
+                    ],
+                ))
+            );
+            assert_eq!(
+                load_instruction_at_checked(1, &account_info),
+                Ok(Instruction::new_with_bytes(
+                    CALLER_PROGRAM_ID,
+                    &[7, 8, 9],
+                    vec![AccountMeta::new_readonly(pubkey, false)],
+                ))
+            );
+        });
+    }
+}
@@ -1,23 +1,252 @@
 #![allow(clippy::not_unsafe_ptr_arg_deref)]
 
+use crate::cpi_trace::CpiRecord;
+use crate::error::TridentStubError;
+use crate::execution_report::ReportStart;
+use crate::sysvars::auto_refresh_sysvar_cache;
+use crate::sysvars::refresh_sysvar_cache_from_accounts;
+
+use std::any::Any;
+use std::cell::Cell;
 use std::cell::RefCell;
-use std::mem::transmute;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::marker::PhantomData;
 
 use solana_program_runtime::invoke_context::InvokeContext;
+use solana_sdk::instruction::InstructionError;
+use solana_sdk::pubkey::Pubkey;
 
+/// Test-controlled values the stubs consult before falling back to the invoke context.
+///
+/// The state belongs to the installation of the invoke context that was current when it was
+/// written, identified by a generation rather than the context's address, which a later
+/// invoke context may reuse. It is reset whenever an invoke context is installed or cleared.
+/// Values written while no invoke context is set are adopted by the next one.
+#[derive(Default)]
+pub(crate) struct ContextState {
+    generation: Option<u64>,
+    pub(crate) epoch_total_stake: Option<u64>,
+    pub(crate) epoch_stakes: HashMap<Pubkey, u64>,
+    pub(crate) sysvars: HashMap<Pubkey, Box<dyn Any>>,
+    pub(crate) sysvar_data: HashMap<Pubkey, Vec<u8>>,
+    /// Sysvars set explicitly, which refreshing from the sysvar accounts leaves alone.
+    pub(crate) pinned_sysvars: HashSet<Pubkey>,
+    pub(crate) compute_unit_limit: Option<u64>,
+    pub(crate) ms_per_slot: Option<u64>,
+    pub(crate) cpi_trace: Vec<CpiRecord>,
+    pub(crate) last_cpi_error: Option<TridentStubError>,
+    pub(crate) report_start: Option<ReportStart>,
+}
 
 thread_local! {
     static INVOKE_CONTEXT: RefCell<Option<usize>> = const { RefCell::new(None) };
+    static CONTEXT_STATE: RefCell<ContextState> = RefCell::new(ContextState::default());
+    static NEXT_GENERATION: Cell<u64> = const { Cell::new(0) };
 }
+
+fn next_generation() -> u64 {
+    NEXT_GENERATION.with(|next_generation| {
+        let generation = next_generation.get();
+        next_generation.set(generation.wrapping_add(1));
+        generation
+    })
+}
+
+/// Installs `new` as the invoke context the stubs use on the current thread. Other threads
+/// keep their own invoke context, so programs can execute on several threads at once.
+///
+/// Every call starts with fresh test-controlled values such as epoch stakes and sysvar
+/// overrides, apart from those set while no invoke context was installed.
+///
+/// With `set_auto_refresh_sysvar_cache` enabled, the served sysvars are then refreshed from
+/// the transaction's sysvar accounts.
+///
+/// The invoke context stays installed after `new` is dropped, and the next syscall
+/// dereferences a dangling pointer unless `clear_invoke_context` is called first. Only
+/// `InvokeContextGuard` and `with_invoke_context` uninstall it reliably, including on panic.
 pub fn set_invoke_context(new: &mut InvokeContext) {
-    INVOKE_CONTEXT.with(|invoke_context| unsafe {
-        invoke_context.replace(Some(transmute::<&mut InvokeContext, usize>(new)))
+    let ptr = new as *mut InvokeContext as usize;
+    INVOKE_CONTEXT.with(|invoke_context| invoke_context.replace(Some(ptr)));
+    CONTEXT_STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        match state.generation {
+            Some(_) => *state = ContextState::default(),
+            // Adopt a compute unit limit set before any invoke context was installed
+            None => {
+                if let Some(limit) = state.compute_unit_limit {
+                    new.mock_set_remaining(limit);
+                }
+            }
+        }
+        state.generation = Some(next_generation());
     });
+    if auto_refresh_sysvar_cache() {
+        // Only fails if a sysvar account is borrowed, which leaves the served sysvars as is
+        let _ = refresh_sysvar_cache_from_accounts();
+    }
 }
+
+/// Uninstalls the invoke context installed with `set_invoke_context` on the current thread,
+/// along with its test-controlled values.
+pub fn clear_invoke_context() {
+    INVOKE_CONTEXT.with(|invoke_context| invoke_context.replace(None));
+    CONTEXT_STATE.with(|state| state.replace(ContextState::default()));
+}
+
+/// Returns the invoke context installed on the current thread.
 pub fn get_invoke_context<'a, 'b>() -> &'a mut InvokeContext<'b> {
-    let ptr = INVOKE_CONTEXT.with(|invoke_context| match *invoke_context.borrow() {
-        Some(val) => val,
-        None => panic!("Invoke context not set!"),
-    });
-    unsafe { transmute::<usize, &mut InvokeContext>(ptr) }
+    try_get_invoke_context().expect(
+        "syscall stubs called outside of program execution; \
+         did you call a solana_program API from test code?",
+    )
+}
+
+/// Returns the current invoke context, or `None` when no program is executing.
+pub fn try_get_invoke_context<'a, 'b>() -> Option<&'a mut InvokeContext<'b>> {
+    let ptr = INVOKE_CONTEXT.with(|invoke_context| *invoke_context.borrow())?;
+    Some(unsafe { &mut *(ptr as *mut InvokeContext) })
+}
+
+pub(crate) fn with_context_state<R>(f: impl FnOnce(&mut ContextState) -> R) -> R {
+    CONTEXT_STATE.with(|state| f(&mut state.borrow_mut()))
+}
+
+/// Keeps an invoke context installed for the stubs until dropped.
+///
+/// Guards nest: dropping one, also while unwinding from a panic, reinstalls the invoke
+/// context and test-controlled values that were current when it was created. Dropping the
+/// outermost guard clears both, so later syscalls cannot reach a context that no longer
+/// exists.
+pub struct InvokeContextGuard<'a> {
+    previous: Option<usize>,
+    previous_state: Option<ContextState>,
+    _invoke_context: PhantomData<&'a mut ()>,
+}
+
+impl<'a> InvokeContextGuard<'a> {
+    pub fn new(invoke_context: &'a mut InvokeContext) -> Self {
+        let ptr = invoke_context as *mut InvokeContext as usize;
+        let previous = INVOKE_CONTEXT.with(|invoke_context| *invoke_context.borrow());
+        let previous_state = match previous {
+            // Nested call on the same invoke context, e.g. a CPI, shares its state
+            Some(previous) if previous == ptr => None,
+            Some(_) => Some(CONTEXT_STATE.with(|state| state.take())),
+            None => Some(ContextState::default()),
+        };
+        if previous_state.is_some() {
+            set_invoke_context(invoke_context);
+        }
+        Self {
+            previous,
+            previous_state,
+            _invoke_context: PhantomData,
+        }
+    }
+}
+
+impl Drop for InvokeContextGuard<'_> {
+    fn drop(&mut self) {
+        INVOKE_CONTEXT.with(|invoke_context| invoke_context.replace(self.previous));
+        if let Some(previous_state) = self.previous_state.take() {
+            CONTEXT_STATE.with(|state| state.replace(previous_state));
+        }
+    }
+}
+
+/// Runs `f` with `invoke_context` installed and clears it once `f` returns or panics.
+pub fn with_invoke_context<R>(invoke_context: &mut InvokeContext, f: impl FnOnce() -> R) -> R {
+    let _guard = InvokeContextGuard::new(invoke_context);
+    f()
+}
+
+/// Sets the stake `sol_get_epoch_stake` reports for `vote_address`.
+pub fn set_epoch_stake(vote_address: &Pubkey, stake: u64) {
+    with_context_state(|state| state.epoch_stakes.insert(*vote_address, stake));
+}
+
+/// Sets the total epoch stake `sol_get_epoch_stake` reports for a null vote address.
+pub fn set_epoch_total_stake(stake: u64) {
+    with_context_state(|state| state.epoch_total_stake = Some(stake));
+}
+
+/// Clears the return data of the current invoke context's transaction. Call this between
+/// top-level instructions so an instruction cannot observe return data set by an earlier
+/// one.
+pub fn clear_return_data() -> Result<(), InstructionError> {
+    match try_get_invoke_context() {
+        Some(invoke_context) => invoke_context
+            .transaction_context
+            .set_return_data(Pubkey::default(), Vec::new()),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::with_mock_caller;
+    use crate::TridentSyscallStubs;
+
+    use solana_sdk::program_stubs::SyscallStubs;
+
+    fn epoch_stake(vote_address: Option<&Pubkey>) -> u64 {
+        let vote_address = vote_address.map_or(std::ptr::null(), |vote_address| {
+            vote_address.as_ref().as_ptr()
+        });
+        TridentSyscallStubs::default().sol_get_epoch_stake(vote_address)
+    }
+
+    #[test]
+    fn epoch_stakes_are_served_until_a_context_is_installed_again() {
+        let vote_address = Pubkey::new_unique();
+        set_epoch_stake(&vote_address, 7);
+
+        with_mock_caller(Vec::new(), &[], || {
+            // Set before the context was installed
+            assert_eq!(epoch_stake(Some(&vote_address)), 7);
+
+            set_epoch_stake(&vote_address, 42);
+            set_epoch_total_stake(100);
+            assert_eq!(epoch_stake(Some(&vote_address)), 42);
+            assert_eq!(epoch_stake(Some(&Pubkey::new_unique())), 0);
+            assert_eq!(epoch_stake(None), 100);
+
+            // Installing a context at the same address starts over
+            set_invoke_context(get_invoke_context());
+            assert_eq!(epoch_stake(Some(&vote_address)), 0);
+            assert_eq!(epoch_stake(None), 0);
+        });
+    }
+
+    #[test]
+    fn no_invoke_context_is_reachable_once_uninstalled() {
+        with_mock_caller(Vec::new(), &[], || {
+            assert!(try_get_invoke_context().is_some());
+        });
+        assert!(try_get_invoke_context().is_none());
+
+        let result = std::panic::catch_unwind(|| {
+            with_mock_caller(Vec::new(), &[], || panic!("program panicked"))
+        });
+        assert!(result.is_err());
+        assert!(try_get_invoke_context().is_none());
+
+        with_mock_caller(Vec::new(), &[], || {
+            let invoke_context = get_invoke_context();
+            let _guard = InvokeContextGuard::new(invoke_context);
+        });
+        assert!(try_get_invoke_context().is_none());
+    }
+
+    #[test]
+    fn clear_invoke_context_uninstalls_a_set_invoke_context() {
+        with_mock_caller(Vec::new(), &[], || {
+            set_invoke_context(get_invoke_context());
+            set_epoch_total_stake(100);
+            clear_invoke_context();
+            assert!(try_get_invoke_context().is_none());
+            assert_eq!(with_context_state(|state| state.epoch_total_stake), None);
+        });
+    }
 }
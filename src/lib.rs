@@ -1,5 +1,43 @@
+pub mod assertions;
+#[cfg(test)]
+mod cargo_lock;
+pub mod compute_units;
+pub mod cpi_trace;
+pub mod error;
+pub mod execution_report;
+pub mod fault_injection;
+pub mod feature_set;
+pub mod instructions_sysvar;
 pub mod invoke_context;
+pub mod logs;
+pub mod precompiles;
+pub mod report_json;
+pub mod sdk_version;
 pub mod syscall_stubs;
+pub mod syscall_trace;
+pub mod sysvar_snapshot;
+pub mod sysvars;
+#[cfg(test)]
+mod test_utils;
 
+pub use assertions::*;
+pub use compute_units::*;
+pub use cpi_trace::*;
+pub use error::*;
+pub use execution_report::*;
+pub use fault_injection::*;
+pub use feature_set::*;
+pub use instructions_sysvar::*;
 pub use invoke_context::*;
+pub use logs::*;
+pub use precompiles::*;
+pub use report_json::*;
+pub use sdk_version::*;
 pub use syscall_stubs::*;
+pub use syscall_trace::*;
+pub use sysvar_snapshot::*;
+pub use sysvars::*;
+
+/// The solana-sdk these stubs are built against. Programs must use the same version for
+/// their `Instruction` and `AccountInfo` to reach the stubs.
+pub use solana_sdk;
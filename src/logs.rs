@@ -0,0 +1,73 @@
+use crate::try_get_invoke_context;
+
+/// Returns the program logs collected so far by the current invoke context.
+///
+/// Logs belong to the invoke context, so they are only reachable while it is installed.
+/// Returns an empty list if there is no invoke context or it has no log collector.
+pub fn peek_program_logs() -> Vec<String> {
+    try_get_invoke_context()
+        .and_then(|invoke_context| invoke_context.get_log_collector())
+        .map(|log_collector| log_collector.borrow().get_recorded_content().to_vec())
+        .unwrap_or_default()
+}
+
+/// Like [`peek_program_logs`], but removes the returned logs from the log collector.
+///
+/// Only the messages are drained; bytes already written still count towards the log
+/// collector's limit.
+pub fn take_program_logs() -> Vec<String> {
+    try_get_invoke_context()
+        .and_then(|invoke_context| invoke_context.get_log_collector())
+        .map(|log_collector| std::mem::take(&mut log_collector.borrow_mut().messages))
+        .unwrap_or_default()
+}
+
+/// Sets how many bytes of logs the current invoke context's log collector accepts before
+/// it records "Log truncated" and drops further messages. `None` removes the limit.
+///
+/// Log collectors start with the mainnet limit of 10,000 bytes.
+pub fn set_log_bytes_limit(bytes_limit: Option<usize>) {
+    if let Some(log_collector) =
+        try_get_invoke_context().and_then(|invoke_context| invoke_context.get_log_collector())
+    {
+        log_collector.borrow_mut().bytes_limit = bytes_limit;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::with_mock_caller;
+    use crate::TridentSyscallStubs;
+
+    use solana_sdk::program_stubs::SyscallStubs;
+
+    #[test]
+    fn logs_past_the_bytes_limit_are_truncated() {
+        let stubs = TridentSyscallStubs::default();
+
+        with_mock_caller(Vec::new(), &[], || {
+            // Two 16 byte messages fit, the third reaches the limit
+            set_log_bytes_limit(Some(40));
+            for _ in 0..4 {
+                stubs.sol_log("abc");
+            }
+            assert_eq!(
+                take_program_logs(),
+                ["Program log: abc", "Program log: abc", "Log truncated"]
+            );
+        });
+
+        with_mock_caller(Vec::new(), &[], || {
+            // Two 18 byte messages fit, the third reaches the limit
+            set_log_bytes_limit(Some(40));
+            for _ in 0..4 {
+                stubs.sol_log_data(&[b"abc"]);
+            }
+            assert_eq!(
+                take_program_logs(),
+                ["Program data: YWJj", "Program data: YWJj", "Log truncated"]
+            );
+        });
+    }
+}
@@ -0,0 +1,188 @@
+use std::sync::Arc;
+
+use solana_sdk::feature_set::FeatureSet;
+use solana_sdk::instruction::InstructionError;
+use solana_sdk::precompiles::get_precompiles;
+use solana_sdk::precompiles::PrecompileError;
+use solana_sdk::pubkey::Pubkey;
+
+use solana_program_runtime::declare_process_instruction;
+use solana_program_runtime::loaded_programs::ProgramCacheEntry;
+use solana_program_runtime::loaded_programs::ProgramCacheForTxBatch;
+
+/// Verifies the signatures of an instruction of the precompile `program_id`, or returns
+/// `None` if `program_id` is no active precompile.
+///
+/// Offsets can only refer to the instruction's own data, which ed25519 offsets do with the
+/// instruction index `u16::MAX`. Any other instruction index is out of range and fails with
+/// the precompile's error for it, so secp256k1 instructions, which always name an
+/// instruction by its index in the transaction, fail as well.
+pub(crate) fn verify_precompile(
+    program_id: &Pubkey,
+    data: &[u8],
+    feature_set: &FeatureSet,
+) -> Option<Result<(), PrecompileError>> {
+    let precompile = get_precompiles().iter().find(|precompile| {
+        precompile.check_id(program_id, |feature_id| feature_set.is_active(feature_id))
+    })?;
+    Some(precompile.verify(data, &[], feature_set))
+}
+
+// Verifies the signatures of an ed25519 or secp256k1 program instruction and fails with the
+// precompile's error code, for harnesses executing precompile instructions like other ones.
+// Builtins must consume compute units, so it charges one
+declare_process_instruction!(PrecompileBuiltin, 1, |invoke_context| {
+    let transaction_context = &invoke_context.transaction_context;
+    let instruction_context = transaction_context.get_current_instruction_context()?;
+    let program_id = instruction_context.get_last_program_key(transaction_context)?;
+    match verify_precompile(
+        program_id,
+        instruction_context.get_instruction_data(),
+        invoke_context.get_feature_set(),
+    ) {
+        Some(result) => result.map_err(|err| InstructionError::Custom(err as u32)),
+        None => Err(InstructionError::IncorrectProgramId),
+    }
+});
+
+/// Registers `PrecompileBuiltin` for every precompile, so instructions of the ed25519 and
+/// secp256k1 programs execute like instructions of other builtins.
+pub fn add_precompile_builtins(program_cache_for_tx_batch: &mut ProgramCacheForTxBatch) {
+    for precompile in get_precompiles() {
+        program_cache_for_tx_batch.replenish(
+            precompile.program_id,
+            Arc::new(ProgramCacheEntry::new_builtin(0, 0, PrecompileBuiltin::vm)),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::get_invoke_context;
+    use crate::take_last_cpi_error;
+    use crate::test_utils::with_mock_caller;
+    use crate::TridentStubError;
+    use crate::TridentSyscallStubs;
+
+    use solana_sdk::ed25519_program;
+    use solana_sdk::instruction::Instruction;
+    use solana_sdk::program_error::ProgramError;
+    use solana_sdk::program_stubs::SyscallStubs;
+    use solana_sdk::secp256k1_program;
+    use solana_sdk::signature::Keypair;
+    use solana_sdk::signature::Signer;
+
+    use solana_program_runtime::timings::ExecuteTimings;
+
+    /// Returns an ed25519 program instruction's data verifying a signature of `message`,
+    /// with every offset referring to the instruction at `instruction_index`.
+    fn ed25519_instruction_data(message: &[u8], instruction_index: u16) -> Vec<u8> {
+        let keypair = Keypair::new();
+        let signature = keypair.sign_message(message);
+        let public_key_offset = 16u16;
+        let signature_offset = public_key_offset + 32;
+        let message_offset = signature_offset + 64;
+
+        let mut data = vec![1, 0];
+        for value in [
+            signature_offset,
+            instruction_index,
+            public_key_offset,
+            instruction_index,
+            message_offset,
+            message.len() as u16,
+            instruction_index,
+        ] {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        data.extend_from_slice(keypair.pubkey().as_ref());
+        data.extend_from_slice(signature.as_ref());
+        data.extend_from_slice(message);
+        data
+    }
+
+    fn invoke_precompile(program_id: Pubkey, data: &[u8]) -> Result<(), ProgramError> {
+        let instruction = Instruction::new_with_bytes(program_id, data, Vec::new());
+        TridentSyscallStubs::default().sol_invoke_signed(&instruction, &[], &[])
+    }
+
+    #[test]
+    fn sol_invoke_signed_verifies_ed25519_signatures() {
+        with_mock_caller(Vec::new(), &[], || {
+            let data = ed25519_instruction_data(b"message", u16::MAX);
+            assert_eq!(invoke_precompile(ed25519_program::id(), &data), Ok(()));
+
+            let mut tampered = data.clone();
+            *tampered.last_mut().unwrap() ^= 1;
+            assert_eq!(
+                invoke_precompile(ed25519_program::id(), &tampered),
+                Err(ProgramError::Custom(
+                    PrecompileError::InvalidSignature as u32
+                ))
+            );
+            assert_eq!(
+                take_last_cpi_error(),
+                Some(TridentStubError::Program(ProgramError::Custom(
+                    PrecompileError::InvalidSignature as u32
+                )))
+            );
+        });
+    }
+
+    #[test]
+    fn sol_invoke_signed_rejects_offsets_into_other_instructions() {
+        with_mock_caller(Vec::new(), &[], || {
+            let data = ed25519_instruction_data(b"message", 0);
+            assert_eq!(
+                invoke_precompile(ed25519_program::id(), &data),
+                Err(ProgramError::Custom(
+                    PrecompileError::InvalidDataOffsets as u32
+                ))
+            );
+
+            // One signature whose offsets all name instruction 0, which secp256k1 reports as
+            // a wrong data size
+            let mut data = vec![1];
+            data.extend_from_slice(&[0; 11]);
+            assert_eq!(
+                invoke_precompile(secp256k1_program::id(), &data),
+                Err(ProgramError::Custom(
+                    PrecompileError::InvalidInstructionDataSize as u32
+                ))
+            );
+        });
+    }
+
+    #[test]
+    fn precompile_builtins_verify_signatures() {
+        let program_id = ed25519_program::id();
+        with_mock_caller(Vec::new(), &[(program_id, PrecompileBuiltin::vm)], || {
+            let invoke_context = get_invoke_context();
+            let program_index = invoke_context
+                .transaction_context
+                .find_index_of_program_account(&program_id)
+                .unwrap();
+            let mut process = |data: &[u8]| {
+                invoke_context.process_instruction(
+                    data,
+                    &[],
+                    &[program_index],
+                    &mut 0,
+                    &mut ExecuteTimings::default(),
+                )
+            };
+
+            let data = ed25519_instruction_data(b"message", u16::MAX);
+            assert_eq!(process(&data), Ok(()));
+            let mut tampered = data;
+            *tampered.last_mut().unwrap() ^= 1;
+            assert_eq!(
+                process(&tampered),
+                Err(InstructionError::Custom(
+                    PrecompileError::InvalidSignature as u32
+                ))
+            );
+        });
+    }
+}
@@ -0,0 +1,247 @@
+use crate::cpi_trace::CpiRecord;
+use crate::execution_report::AccountChange;
+use crate::execution_report::ExecutionReport;
+use crate::fault_injection::InjectedFault;
+
+use std::path::Path;
+
+use base64::prelude::BASE64_STANDARD;
+use base64::Engine;
+use serde::Serialize;
+use solana_sdk::account::AccountSharedData;
+use solana_sdk::account::ReadableAccount;
+use solana_sdk::instruction::InstructionError;
+use solana_sdk::program_error::ProgramError;
+
+/// Version of the schema `ExecutionReport::to_json` follows, increased on incompatible
+/// changes.
+pub const EXECUTION_REPORT_SCHEMA_VERSION: u32 = 1;
+
+#[derive(Serialize)]
+struct ReportJson<'a> {
+    schema_version: u32,
+    program_id: String,
+    error: Option<&'a InstructionError>,
+    compute_units_consumed: u64,
+    return_data: Option<ReturnDataJson>,
+    logs: &'a [String],
+    cpis: Vec<CpiJson>,
+    account_changes: Vec<AccountChangeJson>,
+    injected_faults: Vec<InjectedFaultJson<'a>>,
+}
+
+#[derive(Serialize)]
+struct ReturnDataJson {
+    program_id: String,
+    data: String,
+}
+
+#[derive(Serialize)]
+struct CpiJson {
+    program_id: String,
+    accounts: Vec<AccountMetaJson>,
+    data: String,
+    error: Option<InstructionError>,
+    compute_units_consumed: u64,
+    stack_height: usize,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum InjectedFaultJson<'a> {
+    Invoke {
+        program_id: String,
+        error: &'a ProgramError,
+    },
+    Sysvar {
+        sysvar_id: String,
+    },
+    ReturnData,
+}
+
+#[derive(Serialize)]
+struct AccountMetaJson {
+    pubkey: String,
+    is_signer: bool,
+    is_writable: bool,
+}
+
+#[derive(Serialize)]
+struct AccountChangeJson {
+    pubkey: String,
+    before: AccountJson,
+    after: AccountJson,
+}
+
+#[derive(Serialize)]
+struct AccountJson {
+    lamports: u64,
+    owner: String,
+    executable: bool,
+    rent_epoch: u64,
+    data: String,
+}
+
+impl ExecutionReport {
+    /// Serializes the report to JSON, with pubkeys in base58 and binary data in base64.
+    pub fn to_json(&self) -> String {
+        let report = ReportJson {
+            schema_version: EXECUTION_REPORT_SCHEMA_VERSION,
+            program_id: self.program_id.to_string(),
+            error: self.result.as_ref().err(),
+            compute_units_consumed: self.compute_units_consumed,
+            return_data: self
+                .return_data
+                .as_ref()
+                .map(|(program_id, data)| ReturnDataJson {
+                    program_id: program_id.to_string(),
+                    data: BASE64_STANDARD.encode(data),
+                }),
+            logs: &self.logs,
+            cpis: self.cpis.iter().map(cpi_json).collect(),
+            account_changes: self
+                .account_changes
+                .iter()
+                .map(account_change_json)
+                .collect(),
+            injected_faults: self
+                .injected_faults
+                .iter()
+                .map(injected_fault_json)
+                .collect(),
+        };
+        serde_json::to_string_pretty(&report).expect("reports serialize to JSON")
+    }
+
+    /// Writes the report to `path` as JSON, see `to_json`.
+    pub fn write_report(&self, path: impl AsRef<Path>) -> std::io::Result<()> {
+        std::fs::write(path, self.to_json())
+    }
+}
+
+fn cpi_json(cpi: &CpiRecord) -> CpiJson {
+    CpiJson {
+        program_id: cpi.program_id.to_string(),
+        accounts: cpi
+            .accounts
+            .iter()
+            .map(|account_meta| AccountMetaJson {
+                pubkey: account_meta.pubkey.to_string(),
+                is_signer: account_meta.is_signer,
+                is_writable: account_meta.is_writable,
+            })
+            .collect(),
+        data: BASE64_STANDARD.encode(&cpi.data),
+        error: cpi.result.clone().err().map(InstructionError::from),
+        compute_units_consumed: cpi.compute_units_consumed,
+        stack_height: cpi.stack_height,
+    }
+}
+
+fn injected_fault_json(fault: &InjectedFault) -> InjectedFaultJson<'_> {
+    match fault {
+        InjectedFault::Invoke { program_id, error } => InjectedFaultJson::Invoke {
+            program_id: program_id.to_string(),
+            error,
+        },
+        InjectedFault::Sysvar { sysvar_id } => InjectedFaultJson::Sysvar {
+            sysvar_id: sysvar_id.to_string(),
+        },
+        InjectedFault::ReturnData => InjectedFaultJson::ReturnData,
+    }
+}
+
+fn account_change_json(account_change: &AccountChange) -> AccountChangeJson {
+    AccountChangeJson {
+        pubkey: account_change.pubkey.to_string(),
+        before: account_json(&account_change.before),
+        after: account_json(&account_change.after),
+    }
+}
+
+fn account_json(account: &AccountSharedData) -> AccountJson {
+    AccountJson {
+        lamports: account.lamports(),
+        owner: account.owner().to_string(),
+        executable: account.executable(),
+        rent_epoch: account.rent_epoch(),
+        data: BASE64_STANDARD.encode(account.data()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::begin_execution_report;
+    use crate::clear_fault_config;
+    use crate::end_execution_report;
+    use crate::set_fault_config;
+    use crate::take_last_report;
+    use crate::test_utils::account_infos;
+    use crate::test_utils::serialize_instruction_accounts;
+    use crate::test_utils::with_mock_caller;
+    use crate::test_utils::CALLER_PROGRAM_ID;
+    use crate::FaultConfig;
+    use crate::TridentSyscallStubs;
+
+    use solana_sdk::clock::Clock;
+    use solana_sdk::instruction::AccountMeta;
+    use solana_sdk::instruction::Instruction;
+    use solana_sdk::instruction::InstructionError;
+    use solana_sdk::program_stubs::SyscallStubs;
+    use solana_sdk::pubkey::Pubkey;
+
+    use solana_program_runtime::declare_process_instruction;
+
+    const CALLEE_ID: Pubkey = Pubkey::new_from_array([2; 32]);
+
+    declare_process_instruction!(WriteData, 1, |invoke_context| {
+        let transaction_context = &invoke_context.transaction_context;
+        let instruction_context = transaction_context.get_current_instruction_context()?;
+        let mut account =
+            instruction_context.try_borrow_instruction_account(transaction_context, 0)?;
+        account.set_data_from_slice(instruction_context.get_instruction_data())
+    });
+
+    #[test]
+    fn report_json_matches_the_golden_file() {
+        let pubkey = Pubkey::new_from_array([3; 32]);
+        let account = AccountSharedData::new(1_000_000, 3, &CALLEE_ID);
+
+        let report = with_mock_caller(
+            vec![(pubkey, account)],
+            &[(CALLEE_ID, WriteData::vm)],
+            || {
+                begin_execution_report(&CALLER_PROGRAM_ID);
+                let stubs = TridentSyscallStubs::default();
+
+                set_fault_config(FaultConfig {
+                    sysvar_failure_probability: 1.0,
+                    ..FaultConfig::default()
+                });
+                let mut clock = Clock::default();
+                stubs.sol_get_clock_sysvar(&mut clock as *mut Clock as *mut u8);
+
+                let mut input = serialize_instruction_accounts();
+                let account_infos = account_infos(&mut input);
+                let instruction = Instruction::new_with_bytes(
+                    CALLEE_ID,
+                    &[1, 2, 3],
+                    vec![AccountMeta::new(pubkey, false)],
+                );
+                stubs
+                    .sol_invoke_signed(&instruction, &account_infos, &[])
+                    .unwrap();
+
+                end_execution_report(Err(InstructionError::Custom(42)));
+                clear_fault_config();
+                take_last_report().unwrap()
+            },
+        );
+
+        assert_eq!(
+            report.to_json(),
+            include_str!("../testdata/execution_report.json").trim_end()
+        );
+    }
+}
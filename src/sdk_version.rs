@@ -0,0 +1,50 @@
+/// The solana-sdk version the stubs are built against, as locked in the Cargo.lock of the
+/// build, or `None` if the build found no Cargo.lock.
+pub const SOLANA_SDK_VERSION: Option<&str> = option_env!("TRIDENT_SOLANA_SDK_VERSION");
+
+/// Names the solana-program versions the build locked besides `SOLANA_SDK_VERSION`, if any.
+/// The build warns with the same message.
+pub const SDK_VERSION_MISMATCH: Option<&str> = option_env!("TRIDENT_SDK_VERSION_MISMATCH");
+
+/// Fails to compile unless the program's `AccountInfo` and `StableInstruction` are the ones
+/// the stubs are built against, rather than types of another solana-program version that
+/// the stubs would misread.
+///
+/// A mismatch fails with "mismatched types" and a note that two different versions of
+/// `solana_program` are used; `SDK_VERSION_MISMATCH` and the build warning name both.
+///
+/// Pass the path of the program's solana-program crate if it is not `::solana_program`,
+/// e.g. `assert_sdk_compatibility!(anchor_lang::solana_program)`.
+#[macro_export]
+macro_rules! assert_sdk_compatibility {
+    () => {
+        $crate::assert_sdk_compatibility!(::solana_program);
+    };
+    ($solana_program:path) => {
+        const _: () = {
+            use $crate::solana_sdk as stubs_sdk;
+            use $solana_program as program_sdk;
+
+            const _: fn(
+                program_sdk::account_info::AccountInfo<'static>,
+            ) -> stubs_sdk::account_info::AccountInfo<'static> = |account_info| account_info;
+            const _: fn(
+                program_sdk::stable_layout::stable_instruction::StableInstruction,
+            ) -> stubs_sdk::stable_layout::stable_instruction::StableInstruction =
+                |instruction| instruction;
+        };
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    crate::assert_sdk_compatibility!(solana_sdk);
+
+    #[test]
+    fn the_locked_solana_sdk_version_is_resolved() {
+        assert!(SOLANA_SDK_VERSION.unwrap().starts_with("2.0."));
+        assert_eq!(SDK_VERSION_MISMATCH, None);
+    }
+}
@@ -1,227 +1,477 @@
 #![allow(clippy::not_unsafe_ptr_arg_deref)]
 
+use crate::cpi_trace::CpiRecord;
+use crate::error::TridentStubError;
+use crate::fault_injection::inject_invoke_fault;
+use crate::fault_injection::inject_return_data_fault;
+use crate::fault_injection::inject_sysvar_fault;
+use crate::feature_set::is_feature_active;
 use crate::get_invoke_context;
+use crate::invoke_context::with_context_state;
+use crate::precompiles::verify_precompile;
+use crate::syscall_trace::record;
+use crate::syscall_trace::SyscallEvent;
+use crate::sysvars::current_sysvar;
+use crate::try_get_invoke_context;
 
-use std::mem::transmute;
+use std::collections::HashMap;
 use std::sync::Arc;
 use std::sync::Once;
 
+use base64::prelude::BASE64_STANDARD;
+use base64::Engine;
+
+use solana_sdk::account::ReadableAccount;
 use solana_sdk::account_info::AccountInfo;
+use solana_sdk::bpf_loader;
+use solana_sdk::bpf_loader_deprecated;
+use solana_sdk::bpf_loader_upgradeable;
+use solana_sdk::clock::Clock;
+use solana_sdk::entrypoint::ProgramResult;
+use solana_sdk::entrypoint::MAX_PERMITTED_DATA_INCREASE;
 use solana_sdk::entrypoint::SUCCESS;
+use solana_sdk::feature_set::disable_fees_sysvar;
+use solana_sdk::feature_set::enable_bpf_loader_set_authority_checked_ix;
+use solana_sdk::feature_set::increase_tx_account_lock_limit;
+use solana_sdk::feature_set::loosen_cpi_size_restriction;
+use solana_sdk::instruction::AccountMeta;
 use solana_sdk::instruction::Instruction;
 use solana_sdk::instruction::InstructionError;
+use solana_sdk::native_loader;
+use solana_sdk::program::MAX_RETURN_DATA;
 use solana_sdk::program_error::ProgramError;
 use solana_sdk::program_error::UNSUPPORTED_SYSVAR;
 use solana_sdk::program_stubs;
+use solana_sdk::program_stubs::is_nonoverlapping;
 use solana_sdk::program_stubs::set_syscall_stubs;
 use solana_sdk::pubkey::Pubkey;
-use solana_sdk::stable_layout::stable_instruction::StableInstruction;
 use solana_sdk::sysvar::Sysvar;
+use solana_sdk::sysvar::SysvarId;
+use solana_sdk::transaction_context::IndexOfAccount;
+use solana_sdk::transaction_context::InstructionAccount;
 
+use solana_compute_budget::compute_budget::ComputeBudget;
+use solana_program_runtime::ic_msg;
+use solana_program_runtime::invoke_context::InvokeContext;
+use solana_program_runtime::solana_rbpf::vm::ContextObject;
 use solana_program_runtime::stable_log;
+use solana_program_runtime::sysvar_cache::SysvarCache;
 use solana_program_runtime::timings::ExecuteTimings;
 
+/// Maximum data length of a CPI instruction with `loosen_cpi_size_restriction`.
+const MAX_CPI_INSTRUCTION_DATA_LEN: usize = 10 * 1024;
+/// Maximum number of account metas of a CPI instruction with `loosen_cpi_size_restriction`.
+const MAX_CPI_INSTRUCTION_ACCOUNTS: usize = u8::MAX as usize;
+/// Maximum number of AccountInfos passed to a CPI with `increase_tx_account_lock_limit`.
+const MAX_CPI_ACCOUNT_INFOS: usize = 128;
+/// Return code of `sol_get_sysvar` when the requested range is out of bounds.
+const OFFSET_LENGTH_EXCEEDS_SYSVAR: u64 = 1;
+/// Return code of `sol_get_sysvar` when the sysvar is not in the cache.
+const SYSVAR_NOT_FOUND: u64 = 2;
+
 static ONCE: Once = Once::new();
 
+/// Installs the default stubs, unless stubs were already installed through this crate.
 pub fn set_stubs_v2() {
     ONCE.call_once(|| {
-        set_syscall_stubs(Box::new(TridentSyscallStubs {}));
+        set_syscall_stubs(Box::new(TridentSyscallStubs::default()));
     });
 }
 
-pub struct TridentSyscallStubs;
+type ClockHook = Box<dyn Fn() -> Clock + Send + Sync>;
+type InvokeHook = Box<dyn Fn(&Instruction, &[AccountInfo]) -> Option<ProgramResult> + Send + Sync>;
+type LogSink = Box<dyn Fn(&str) + Send + Sync>;
+
+#[derive(Default)]
+pub struct TridentSyscallStubs {
+    clock: Option<ClockHook>,
+    invoke_hook: Option<InvokeHook>,
+    log_sink: Option<LogSink>,
+    cpi_dump: Option<usize>,
+    forward_logs: bool,
+}
+
+impl TridentSyscallStubs {
+    pub fn builder() -> TridentSyscallStubsBuilder {
+        TridentSyscallStubsBuilder::default()
+    }
+
+    /// Installs these stubs in place of the current ones. A later `set_stubs_v2` keeps them.
+    pub fn install(self) {
+        ONCE.call_once(|| {});
+        set_syscall_stubs(Box::new(self));
+    }
+}
+
+/// Builds `TridentSyscallStubs` with individual syscalls replaced by closures. Syscalls
+/// without a closure keep the default behavior.
+#[derive(Default)]
+pub struct TridentSyscallStubsBuilder {
+    stubs: TridentSyscallStubs,
+}
+
+impl TridentSyscallStubsBuilder {
+    /// Serves the Clock returned by `clock` from `sol_get_clock_sysvar`.
+    pub fn with_clock(mut self, clock: impl Fn() -> Clock + Send + Sync + 'static) -> Self {
+        self.stubs.clock = Some(Box::new(clock));
+        self
+    }
+
+    /// Calls `hook` at the start of every `sol_invoke_signed`. Returning `Some` ends the CPI
+    /// with that result, returning `None` performs it as usual.
+    pub fn with_invoke_hook(
+        mut self,
+        hook: impl Fn(&Instruction, &[AccountInfo]) -> Option<ProgramResult> + Send + Sync + 'static,
+    ) -> Self {
+        self.stubs.invoke_hook = Some(Box::new(hook));
+        self
+    }
+
+    /// Passes `sol_log` messages to `sink` instead of the invoke context's log collector.
+    pub fn with_log_sink(mut self, sink: impl Fn(&str) + Send + Sync + 'static) -> Self {
+        self.stubs.log_sink = Some(Box::new(sink));
+        self
+    }
+
+    /// Logs the callee, resolved accounts and data of every CPI before it is processed, with
+    /// the data hex-encoded up to `max_data_len` bytes.
+    pub fn with_cpi_dump(mut self, max_data_len: usize) -> Self {
+        self.stubs.cpi_dump = Some(max_data_len);
+        self
+    }
+
+    /// Also emits every `sol_log` and `sol_log_data` line as a `log` record with the target
+    /// `trident::program::<program id>` and the stack height in the message, so logs can be
+    /// filtered per program with `RUST_LOG`.
+    pub fn with_log_forwarding(mut self) -> Self {
+        self.stubs.forward_logs = true;
+        self
+    }
+
+    pub fn build(self) -> TridentSyscallStubs {
+        self.stubs
+    }
+}
 
 impl program_stubs::SyscallStubs for TridentSyscallStubs {
     fn sol_log(&self, message: &str) {
+        record(|| SyscallEvent::Log {
+            message: message.to_string(),
+        });
+
+        if self.forward_logs {
+            forward_log(&format!("Program log: {}", message));
+        }
+
+        if let Some(log_sink) = &self.log_sink {
+            log_sink(message);
+            return;
+        }
+
         let invoke_context = get_invoke_context();
         let log_collector = invoke_context.get_log_collector();
 
         stable_log::program_log(&log_collector, message);
     }
 
+    fn sol_log_data(&self, fields: &[&[u8]]) {
+        record(|| SyscallEvent::LogData {
+            field_lengths: fields.iter().map(|field| field.len()).collect(),
+        });
+
+        if self.forward_logs {
+            let fields = fields
+                .iter()
+                .map(|field| BASE64_STANDARD.encode(field))
+                .collect::<Vec<_>>();
+            forward_log(&format!("Program data: {}", fields.join(" ")));
+        }
+
+        let invoke_context = get_invoke_context();
+        let log_collector = invoke_context.get_log_collector();
+
+        stable_log::program_data(&log_collector, fields);
+    }
+
+    fn sol_log_compute_units(&self) {
+        record(|| SyscallEvent::LogComputeUnits);
+
+        let invoke_context = get_invoke_context();
+
+        ic_msg!(
+            invoke_context,
+            "Program consumption: {} units remaining",
+            invoke_context.get_remaining()
+        );
+    }
+
+    fn sol_remaining_compute_units(&self) -> u64 {
+        record(|| SyscallEvent::RemainingComputeUnits);
+
+        get_invoke_context().get_remaining()
+    }
+
+    fn sol_get_sysvar(
+        &self,
+        sysvar_id_addr: *const u8,
+        var_addr: *mut u8,
+        offset: u64,
+        length: u64,
+    ) -> u64 {
+        let sysvar_id = unsafe { &*(sysvar_id_addr as *const Pubkey) };
+        record(|| SyscallEvent::GetSysvarSlice {
+            sysvar_id: *sysvar_id,
+            offset,
+            length,
+        });
+        if inject_sysvar_fault(sysvar_id) {
+            return SYSVAR_NOT_FOUND;
+        }
+        sysvar_consume(|compute_budget| {
+            let sysvar_id_cost = (size_of::<Pubkey>() as u64)
+                .checked_div(compute_budget.cpi_bytes_per_unit)
+                .unwrap_or(0);
+            let sysvar_buf_cost = length
+                .checked_div(compute_budget.cpi_bytes_per_unit)
+                .unwrap_or(0);
+            compute_budget
+                .sysvar_base_cost
+                .saturating_add(sysvar_id_cost)
+                .saturating_add(sysvar_buf_cost.max(compute_budget.mem_op_base_cost))
+        });
+
+        // Copy from the serialized account data rather than the deserialized object, so
+        // variable-length sysvars are served in full or in chunks. Of the solana-program 2.0
+        // sysvar APIs only SlotHashesSysvar reads through this syscall, other sysvars such as
+        // StakeHistory are only served to programs calling it directly.
+        let Some(offset_length) = offset.checked_add(length) else {
+            return OFFSET_LENGTH_EXCEEDS_SYSVAR;
+        };
+
+        let copy = |sysvar_buf: &[u8]| match sysvar_buf.get(offset as usize..offset_length as usize)
+        {
+            Some(sysvar_slice) => unsafe {
+                std::ptr::copy_nonoverlapping(sysvar_slice.as_ptr(), var_addr, sysvar_slice.len());
+                SUCCESS
+            },
+            None => OFFSET_LENGTH_EXCEEDS_SYSVAR,
+        };
+
+        if let Some(result) =
+            with_context_state(|state| state.sysvar_data.get(sysvar_id).map(|data| copy(data)))
+        {
+            return result;
+        }
+
+        let Some(invoke_context) = try_get_invoke_context() else {
+            return SYSVAR_NOT_FOUND;
+        };
+        match invoke_context
+            .get_sysvar_cache()
+            .sysvar_id_to_buffer(sysvar_id)
+        {
+            Some(sysvar_buf) => copy(sysvar_buf),
+            None => SYSVAR_NOT_FOUND,
+        }
+    }
+
     fn sol_get_rent_sysvar(&self, var_addr: *mut u8) -> u64 {
-        get_sysvar(get_invoke_context().get_sysvar_cache().get_rent(), var_addr)
+        get_sysvar(SysvarCache::get_rent, var_addr)
     }
     fn sol_get_clock_sysvar(&self, var_addr: *mut u8) -> u64 {
-        get_sysvar(
-            get_invoke_context().get_sysvar_cache().get_clock(),
-            var_addr,
-        )
+        if let Some(clock) = &self.clock {
+            record(|| SyscallEvent::GetSysvar {
+                sysvar_id: Clock::id(),
+            });
+            if inject_sysvar_fault(&Clock::id()) {
+                return UNSUPPORTED_SYSVAR;
+            }
+            sysvar_consume(|compute_budget| {
+                compute_budget
+                    .sysvar_base_cost
+                    .saturating_add(size_of::<Clock>() as u64)
+            });
+            unsafe { std::ptr::write_unaligned(var_addr as *mut Clock, clock()) };
+            return SUCCESS;
+        }
+        get_sysvar(SysvarCache::get_clock, var_addr)
     }
 
     fn sol_get_epoch_schedule_sysvar(&self, var_addr: *mut u8) -> u64 {
-        get_sysvar(
-            get_invoke_context().get_sysvar_cache().get_epoch_schedule(),
-            var_addr,
-        )
+        get_sysvar(SysvarCache::get_epoch_schedule, var_addr)
     }
 
     fn sol_get_epoch_rewards_sysvar(&self, var_addr: *mut u8) -> u64 {
-        get_sysvar(
-            get_invoke_context().get_sysvar_cache().get_epoch_rewards(),
-            var_addr,
-        )
+        get_sysvar(SysvarCache::get_epoch_rewards, var_addr)
     }
     #[allow(deprecated)]
     fn sol_get_fees_sysvar(&self, var_addr: *mut u8) -> u64 {
-        get_sysvar(get_invoke_context().get_sysvar_cache().get_fees(), var_addr)
+        // The syscall is not registered once the fees sysvar is disabled
+        if is_feature_active(&disable_fees_sysvar::id()) {
+            return UNSUPPORTED_SYSVAR;
+        }
+        get_sysvar(SysvarCache::get_fees, var_addr)
     }
 
     fn sol_get_last_restart_slot(&self, var_addr: *mut u8) -> u64 {
-        get_sysvar(
-            get_invoke_context()
-                .get_sysvar_cache()
-                .get_last_restart_slot(),
-            var_addr,
-        )
+        get_sysvar(SysvarCache::get_last_restart_slot, var_addr)
     }
+    fn sol_get_epoch_stake(&self, vote_address: *const u8) -> u64 {
+        record(|| SyscallEvent::GetEpochStake {
+            vote_address: (!vote_address.is_null())
+                .then(|| unsafe { *(vote_address as *const Pubkey) }),
+        });
+
+        let invoke_context = get_invoke_context();
+
+        if vote_address.is_null() {
+            with_context_state(|state| state.epoch_total_stake)
+                .or_else(|| invoke_context.get_epoch_total_stake())
+                .unwrap_or(0)
+        } else {
+            let vote_address = unsafe { &*(vote_address as *const Pubkey) };
+
+            with_context_state(|state| state.epoch_stakes.get(vote_address).copied())
+                .or_else(|| {
+                    invoke_context
+                        .get_epoch_vote_accounts()
+                        .and_then(|vote_accounts| vote_accounts.get(vote_address))
+                        .map(|(stake, _)| *stake)
+                })
+                .unwrap_or(0)
+        }
+    }
+
     fn sol_invoke_signed(
         &self,
         instruction: &Instruction,
         account_infos: &[AccountInfo<'_>],
         signers_seeds: &[&[&[u8]]],
     ) -> std::result::Result<(), ProgramError> {
-        let instruction = unsafe { transmute::<&Instruction, &Instruction>(instruction) };
+        record(|| SyscallEvent::Invoke {
+            program_id: instruction.program_id,
+            accounts: instruction.accounts.len(),
+            data_len: instruction.data.len(),
+            signers: signers_seeds.len(),
+        });
 
-        let account_infos =
-            unsafe { transmute::<&[AccountInfo<'_>], &[AccountInfo<'_>]>(account_infos) };
+        if let Some(error) = inject_invoke_fault(&instruction.program_id) {
+            return Err(error);
+        }
+
+        if let Some(result) = self
+            .invoke_hook
+            .as_ref()
+            .and_then(|hook| hook(instruction, account_infos))
+        {
+            return result;
+        }
 
-        let instruction = StableInstruction::from(instruction.clone());
         let invoke_context = get_invoke_context();
-        let log_collector = invoke_context.get_log_collector();
-        let transaction_context = &invoke_context.transaction_context;
-        let instruction_context = transaction_context
-            .get_current_instruction_context()
-            .unwrap();
-        let caller = instruction_context
-            .get_last_program_key(transaction_context)
-            .unwrap();
+        let index_in_trace = with_context_state(|state| state.cpi_trace.len());
+        let stack_height = invoke_context.get_stack_height().saturating_add(1);
+        let compute_units_available = invoke_context.get_remaining();
 
-        stable_log::program_invoke(
-            &log_collector,
-            &instruction.program_id,
-            invoke_context.get_stack_height(),
-        );
+        let result = invoke_signed(instruction, account_infos, signers_seeds, self.cpi_dump);
 
-        let signers = signers_seeds
-            .iter()
-            .map(|seeds| Pubkey::create_program_address(seeds, caller).unwrap())
-            .collect::<Vec<_>>();
+        // Insert rather than push, so the CPI precedes the nested CPIs it made
+        let compute_units_consumed =
+            compute_units_available.saturating_sub(get_invoke_context().get_remaining());
+        with_context_state(|state| {
+            state.cpi_trace.insert(
+                index_in_trace,
+                CpiRecord {
+                    program_id: instruction.program_id,
+                    accounts: instruction.accounts.clone(),
+                    data: instruction.data.clone(),
+                    result: result.clone(),
+                    compute_units_consumed,
+                    stack_height,
+                },
+            );
+            if let Err(error) = &result {
+                state.last_cpi_error = Some(error.clone());
+            }
+        });
 
-        let (instruction_accounts, program_indices) = invoke_context
-            .prepare_instruction(&instruction, &signers)
-            .unwrap();
+        result.map_err(ProgramError::from)
+    }
+    unsafe fn sol_memcpy(&self, dst: *mut u8, src: *const u8, n: usize) {
+        record(|| SyscallEvent::Memcpy { n });
 
-        // Copy caller's account_info modifications into invoke_context accounts
-        let transaction_context = &invoke_context.transaction_context;
-        let instruction_context = transaction_context
-            .get_current_instruction_context()
-            .unwrap();
+        mem_op_consume(n);
 
-        let mut account_indices = Vec::with_capacity(instruction_accounts.len());
-        for instruction_account in instruction_accounts.iter() {
-            let account_key = transaction_context
-                .get_key_of_account_at_index(instruction_account.index_in_transaction)
-                .unwrap();
-            let account_info_index = account_infos
-                .iter()
-                .position(|account_info| account_info.unsigned_key() == account_key)
-                .ok_or(InstructionError::MissingAccount)
-                .unwrap();
-            let account_info = &account_infos[account_info_index];
-            let mut borrowed_account = instruction_context
-                .try_borrow_instruction_account(
-                    transaction_context,
-                    instruction_account.index_in_caller,
-                )
-                .unwrap();
-            if borrowed_account.get_lamports() != account_info.lamports() {
-                borrowed_account
-                    .set_lamports(account_info.lamports())
-                    .unwrap();
-            }
-            let account_info_data = account_info.try_borrow_data().unwrap();
-            // The redundant check helps to avoid the expensive data comparison if we can
-            match borrowed_account
-                .can_data_be_resized(account_info_data.len())
-                .and_then(|_| borrowed_account.can_data_be_changed())
-            {
-                Ok(()) => borrowed_account
-                    .set_data_from_slice(&account_info_data)
-                    .unwrap(),
-                Err(err) if borrowed_account.get_data() != *account_info_data => {
-                    panic!("{err:?}");
-                }
-                _ => {}
-            }
-            // Change the owner at the end so that we are allowed to change the lamports and data before
-            if borrowed_account.get_owner() != account_info.owner {
-                borrowed_account
-                    .set_owner(account_info.owner.as_ref())
-                    .unwrap();
-            }
-            if instruction_account.is_writable {
-                account_indices.push((instruction_account.index_in_caller, account_info_index));
-            }
-        }
+        assert!(
+            is_nonoverlapping(src as usize, n, dst as usize, n),
+            "Overlapping copy"
+        );
+        std::ptr::copy_nonoverlapping(src, dst, n);
+    }
 
-        let mut compute_units_consumed = 0;
+    unsafe fn sol_memmove(&self, dst: *mut u8, src: *const u8, n: usize) {
+        record(|| SyscallEvent::Memmove { n });
 
-        invoke_context
-            .process_instruction(
-                &instruction.data,
-                &instruction_accounts,
-                &program_indices,
-                &mut compute_units_consumed,
-                &mut ExecuteTimings::default(),
-            )
-            .map_err(|e| convert_error(e).unwrap_or_else(|err| panic!("{}", err)))?;
+        mem_op_consume(n);
 
-        // Copy invoke_context accounts modifications into caller's account_info
-        let transaction_context = &invoke_context.transaction_context;
-        let instruction_context = transaction_context
-            .get_current_instruction_context()
-            .unwrap();
-        for (index_in_caller, account_info_index) in account_indices.into_iter() {
-            let borrowed_account = instruction_context
-                .try_borrow_instruction_account(transaction_context, index_in_caller)
-                .unwrap();
-            let account_info = &account_infos[account_info_index];
-            **account_info.try_borrow_mut_lamports().unwrap() = borrowed_account.get_lamports();
-            if account_info.owner != borrowed_account.get_owner() {
-                // TODO Figure out a better way to allow the System Program to set the account owner
-                #[allow(clippy::transmute_ptr_to_ptr)]
-                #[allow(mutable_transmutes)]
-                let account_info_mut =
-                    unsafe { transmute::<&Pubkey, &mut Pubkey>(account_info.owner) };
-                *account_info_mut = *borrowed_account.get_owner();
-            }
+        std::ptr::copy(src, dst, n);
+    }
 
-            let new_data = borrowed_account.get_data();
-            let new_len = new_data.len();
+    unsafe fn sol_memcmp(&self, s1: *const u8, s2: *const u8, n: usize, result: *mut i32) {
+        record(|| SyscallEvent::Memcmp { n });
 
-            // Resize account_info data
-            if account_info.data_len() != new_len {
-                account_info.realloc(new_len, false).unwrap();
-            }
+        mem_op_consume(n);
 
-            // Clone the data
-            let mut data = account_info.try_borrow_mut_data().unwrap();
+        *result = if n == 0 {
+            0
+        } else {
+            let s1 = std::slice::from_raw_parts(s1, n);
+            let s2 = std::slice::from_raw_parts(s2, n);
+            s1.iter()
+                .zip(s2)
+                .find(|(a, b)| a != b)
+                .map(|(a, b)| *a as i32 - *b as i32)
+                .unwrap_or(0)
+        };
+    }
 
-            data.clone_from_slice(new_data);
-        }
+    unsafe fn sol_memset(&self, s: *mut u8, c: u8, n: usize) {
+        record(|| SyscallEvent::Memset { n });
 
-        stable_log::program_success(&log_collector, &instruction.program_id);
+        mem_op_consume(n);
 
-        Ok(())
+        std::ptr::write_bytes(s, c, n);
     }
+
     fn sol_get_return_data(&self) -> std::option::Option<(Pubkey, std::vec::Vec<u8>)> {
+        record(|| SyscallEvent::GetReturnData);
+
+        if inject_return_data_fault() {
+            return None;
+        }
+
         let (program_id, data) = get_invoke_context().transaction_context.get_return_data();
 
-        let program_id = unsafe { transmute::<&Pubkey, &Pubkey>(program_id) };
+        // Like the syscall, report no return data when nothing (or an empty slice) was set
+        if data.is_empty() {
+            return None;
+        }
+
         Some((*program_id, data.to_vec()))
     }
     fn sol_set_return_data(&self, data: &[u8]) {
+        record(|| SyscallEvent::SetReturnData {
+            data_len: data.len(),
+        });
+
+        // The syscall aborts the instruction instead of returning an error
+        if data.len() > MAX_RETURN_DATA {
+            panic!(
+                "Return data too large ({} > {})",
+                data.len(),
+                MAX_RETURN_DATA
+            );
+        }
+
         let invoke_context = get_invoke_context();
         let transaction_context = &mut invoke_context.transaction_context;
         let instruction_context = transaction_context
@@ -236,143 +486,2188 @@ impl program_stubs::SyscallStubs for TridentSyscallStubs {
     }
 
     fn sol_get_stack_height(&self) -> u64 {
+        record(|| SyscallEvent::GetStackHeight);
+
         let invoke_context = get_invoke_context();
         invoke_context.get_stack_height().try_into().unwrap()
     }
 }
 
-fn get_sysvar<T: Default + Sysvar + Sized + serde::de::DeserializeOwned + Clone>(
-    sysvar: Result<Arc<T>, InstructionError>,
-    var_addr: *mut u8,
-) -> u64 {
-    match sysvar {
-        Ok(sysvar_data) => unsafe {
-            *(var_addr as *mut _ as *mut T) = T::clone(&sysvar_data);
-            SUCCESS
-        },
-        Err(_) => UNSUPPORTED_SYSVAR,
+fn invoke_signed(
+    instruction: &Instruction,
+    account_infos: &[AccountInfo],
+    signers_seeds: &[&[&[u8]]],
+    cpi_dump: Option<usize>,
+) -> Result<(), TridentStubError> {
+    let invoke_context = get_invoke_context();
+
+    // Charge the base CPI cost and the program address derivation of every signer
+    let compute_budget = invoke_context.get_compute_budget();
+    let invoke_cost = compute_budget.invoke_units.saturating_add(
+        compute_budget
+            .create_program_address_units
+            .saturating_mul(signers_seeds.len() as u64),
+    );
+    invoke_context.consume_checked(invoke_cost).map_err(|_| {
+        TridentStubError::InvalidInvocation(InstructionError::ComputationalBudgetExceeded)
+    })?;
+
+    if invoke_context.get_stack_height() >= compute_budget.max_instruction_stack_depth {
+        return Err(TridentStubError::InvalidInvocation(
+            InstructionError::CallDepth,
+        ));
     }
-}
 
-fn convert_error(
-    error: InstructionError,
-) -> Result<ProgramError, solana_sdk::instruction::InstructionError> {
-    match error {
-        InstructionError::GenericError => {
-            Err(solana_sdk::instruction::InstructionError::GenericError)
+    check_instruction_size(invoke_context, instruction, account_infos)?;
+    check_authorized_program(invoke_context, instruction)?;
+
+    // Precompiles have no processor to invoke, so verify their signatures natively
+    if let Some(result) = verify_precompile(
+        &instruction.program_id,
+        &instruction.data,
+        invoke_context.get_feature_set(),
+    ) {
+        return result.map_err(|err| ProgramError::Custom(err as u32).into());
+    }
+
+    let transaction_context = &invoke_context.transaction_context;
+    let instruction_context = transaction_context
+        .get_current_instruction_context()
+        .map_err(TridentStubError::InvalidInvocation)?;
+    let caller = instruction_context
+        .get_last_program_key(transaction_context)
+        .map_err(TridentStubError::InvalidInvocation)?;
+
+    let signers = signers_seeds
+        .iter()
+        .map(|seeds| Pubkey::create_program_address(seeds, caller))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(ProgramError::from)?;
+
+    // Resolve each key to its first AccountInfo, like a linear search would
+    let mut account_info_indices = HashMap::with_capacity(account_infos.len());
+    for (index, account_info) in account_infos.iter().enumerate() {
+        account_info_indices
+            .entry(account_info.key)
+            .or_insert(index);
+    }
+
+    check_account_infos(
+        invoke_context,
+        instruction,
+        account_infos,
+        &account_info_indices,
+    )?;
+
+    let (instruction_accounts, program_indices) =
+        prepare_instruction(invoke_context, instruction, &signers)
+            .map_err(TridentStubError::InvalidInvocation)?;
+
+    if let Some(max_data_len) = cpi_dump {
+        dump_instruction(
+            invoke_context,
+            instruction,
+            &instruction_accounts,
+            max_data_len,
+        );
+    }
+
+    // Copy caller's account_info modifications into invoke_context accounts
+    let transaction_context = &invoke_context.transaction_context;
+    let instruction_context = transaction_context
+        .get_current_instruction_context()
+        .map_err(TridentStubError::InvalidInvocation)?;
+
+    let mut account_indices = Vec::with_capacity(instruction_accounts.len());
+    for (index_in_instruction, instruction_account) in instruction_accounts.iter().enumerate() {
+        // Duplicates share the first occurrence's account, so they are synced only once
+        if instruction_account.index_in_callee as usize != index_in_instruction {
+            continue;
+        }
+        let account_key = transaction_context
+            .get_key_of_account_at_index(instruction_account.index_in_transaction)
+            .map_err(TridentStubError::InvalidInvocation)?;
+        let account_info_index =
+            account_info_indices
+                .get(account_key)
+                .copied()
+                .ok_or_else(|| {
+                    ic_msg!(
+                        invoke_context,
+                        "Instruction references an unknown account {}",
+                        account_key
+                    );
+                    TridentStubError::AccountNotProvided {
+                        pubkey: *account_key,
+                    }
+                })?;
+        let account_info = &account_infos[account_info_index];
+        let mut borrowed_account = instruction_context
+            .try_borrow_instruction_account(
+                transaction_context,
+                instruction_account.index_in_caller,
+            )
+            .map_err(TridentStubError::InvalidInvocation)?;
+        if borrowed_account.get_lamports() != account_info.lamports() {
+            borrowed_account
+                .set_lamports(account_info.lamports())
+                .map_err(TridentStubError::InvalidInvocation)?;
+        }
+        let account_info_data = account_info.try_borrow_data()?;
+        // The redundant check helps to avoid the expensive data comparison if we can
+        match borrowed_account
+            .can_data_be_resized(account_info_data.len())
+            .and_then(|_| borrowed_account.can_data_be_changed())
+        {
+            Ok(()) => borrowed_account
+                .set_data_from_slice(&account_info_data)
+                .map_err(TridentStubError::InvalidInvocation)?,
+            // Only reached for accounts the caller may not modify. Slice comparison checks
+            // the lengths first and stops at the first differing byte
+            Err(err) if borrowed_account.get_data() != *account_info_data => {
+                return Err(TridentStubError::InvalidInvocation(err));
+            }
+            _ => {}
         }
-        InstructionError::InvalidArgument => Ok(ProgramError::InvalidArgument),
-        InstructionError::InvalidInstructionData => Ok(ProgramError::InvalidInstructionData),
-        InstructionError::InvalidAccountData => Ok(ProgramError::InvalidAccountData),
-        InstructionError::AccountDataTooSmall => Ok(ProgramError::AccountDataTooSmall),
-        InstructionError::InsufficientFunds => Ok(ProgramError::InsufficientFunds),
-        InstructionError::IncorrectProgramId => Ok(ProgramError::IncorrectProgramId),
-        InstructionError::MissingRequiredSignature => Ok(ProgramError::MissingRequiredSignature),
-        InstructionError::AccountAlreadyInitialized => Ok(ProgramError::AccountAlreadyInitialized),
-        InstructionError::UninitializedAccount => Ok(ProgramError::UninitializedAccount),
-        InstructionError::UnbalancedInstruction => {
-            Err(solana_sdk::instruction::InstructionError::UnbalancedInstruction)
+        // Change the owner at the end so that we are allowed to change the lamports and data before
+        if borrowed_account.get_owner() != account_info.owner {
+            borrowed_account
+                .set_owner(account_info.owner.as_ref())
+                .map_err(TridentStubError::InvalidInvocation)?;
         }
-        InstructionError::ModifiedProgramId => {
-            Err(solana_sdk::instruction::InstructionError::ModifiedProgramId)
+        if instruction_account.is_writable {
+            drop(borrowed_account);
+            // Holding a copy makes the callee's first write reallocate the account's shared
+            // data buffer, so a buffer still in place afterwards means the data is unchanged
+            let account = transaction_context
+                .get_account_at_index(instruction_account.index_in_transaction)
+                .map_err(TridentStubError::InvalidInvocation)?
+                .try_borrow()
+                .map_err(|_| {
+                    TridentStubError::InvalidInvocation(InstructionError::AccountBorrowFailed)
+                })?
+                .clone();
+            account_indices.push((
+                instruction_account.index_in_caller,
+                account_info_index,
+                account_info.data_len(),
+                account,
+            ));
         }
-        InstructionError::ExternalAccountLamportSpend => {
-            Err(solana_sdk::instruction::InstructionError::ExecutableLamportChange)
+    }
+
+    let mut compute_units_consumed = 0;
+    invoke_context
+        .process_instruction(
+            &instruction.data,
+            &instruction_accounts,
+            &program_indices,
+            &mut compute_units_consumed,
+            &mut ExecuteTimings::default(),
+        )
+        .map_err(TridentStubError::CalleeFailed)?;
+
+    // Copy invoke_context accounts modifications into caller's account_info
+    let transaction_context = &invoke_context.transaction_context;
+    let instruction_context = transaction_context
+        .get_current_instruction_context()
+        .map_err(TridentStubError::InvalidInvocation)?;
+    // Check the growth of every account, and that its AccountInfo can be resized to match,
+    // before writing any back, so a rejected CPI leaves all of the caller's AccountInfos
+    // untouched
+    for (index_in_caller, account_info_index, original_data_len, _) in &account_indices {
+        let new_len = instruction_context
+            .try_borrow_instruction_account(transaction_context, *index_in_caller)
+            .map_err(TridentStubError::InvalidInvocation)?
+            .get_data()
+            .len();
+        if new_len > original_data_len.saturating_add(MAX_PERMITTED_DATA_INCREASE) {
+            ic_msg!(
+                invoke_context,
+                "Account data size realloc limited to {} in inner instructions",
+                MAX_PERMITTED_DATA_INCREASE
+            );
+            return Err(ProgramError::InvalidRealloc.into());
+        }
+        // AccountInfo::realloc allows growth up to the length the entrypoint serialized,
+        // which it reads from the input, plus the padding behind the account's data. Like
+        // realloc, this relies on the AccountInfo pointing into the entrypoint's input
+        let account_info = &account_infos[*account_info_index];
+        if new_len != account_info.data_len()
+            && new_len.saturating_sub(unsafe { account_info.original_data_len() })
+                > MAX_PERMITTED_DATA_INCREASE
+        {
+            ic_msg!(
+                invoke_context,
+                "Failed to resize account {} to {} bytes",
+                account_info.key,
+                new_len
+            );
+            return Err(ProgramError::InvalidRealloc.into());
         }
-        InstructionError::ExternalAccountDataModified => {
-            Err(solana_sdk::instruction::InstructionError::ExternalAccountDataModified)
+    }
+    for (index_in_caller, account_info_index, _, account_before) in account_indices.into_iter() {
+        let borrowed_account = instruction_context
+            .try_borrow_instruction_account(transaction_context, index_in_caller)
+            .map_err(TridentStubError::InvalidInvocation)?;
+        let account_info = &account_infos[account_info_index];
+        **account_info.try_borrow_mut_lamports()? = borrowed_account.get_lamports();
+        if account_info.owner != borrowed_account.get_owner() {
+            account_info.assign(borrowed_account.get_owner());
         }
-        InstructionError::ReadonlyLamportChange => {
-            Err(solana_sdk::instruction::InstructionError::ReadonlyLamportChange)
+        // AccountInfo's rent epoch and executable flag are immutable, so changes of them by
+        // the callee are only visible in the transaction account, as for a program executing
+        // on chain
+
+        let new_data = borrowed_account.get_data();
+        if new_data.as_ptr() == account_before.data().as_ptr() {
+            continue;
+        }
+        let new_len = new_data.len();
+
+        // Resize account_info data
+        let resized = account_info.data_len() != new_len;
+        if resized {
+            // Zero the truncated tail so that growing the account later exposes zeroes
+            if new_len < account_info.data_len() {
+                account_info.try_borrow_mut_data()?[new_len..].fill(0);
+            }
+            account_info.realloc(new_len, false)?;
+        }
+
+        account_info
+            .try_borrow_mut_data()?
+            .clone_from_slice(new_data);
+    }
+
+    Ok(())
+}
+
+/// Applies the runtime's limits on the size of a CPI. Exceeding them aborts the caller on
+/// chain, so they fail the CPI with `ProgramFailedToComplete`.
+fn check_instruction_size(
+    invoke_context: &InvokeContext,
+    instruction: &Instruction,
+    account_infos: &[AccountInfo],
+) -> Result<(), TridentStubError> {
+    let feature_set = invoke_context.get_feature_set();
+    let max_cpi_instruction_size = invoke_context.get_compute_budget().max_cpi_instruction_size;
+    let num_accounts = instruction.accounts.len();
+    let data_len = instruction.data.len();
+    let num_account_infos = account_infos.len();
+
+    let fail = |message: String| {
+        ic_msg!(invoke_context, "{}", message);
+        Err(TridentStubError::InvalidInvocation(
+            InstructionError::ProgramFailedToComplete,
+        ))
+    };
+
+    if feature_set.is_active(&loosen_cpi_size_restriction::id()) {
+        if data_len > MAX_CPI_INSTRUCTION_DATA_LEN {
+            return fail(format!(
+                "Invoked an instruction with data that is too large ({} > {})",
+                data_len, MAX_CPI_INSTRUCTION_DATA_LEN
+            ));
         }
-        InstructionError::ReadonlyDataModified => {
-            Err(solana_sdk::instruction::InstructionError::ReadonlyDataModified)
+        if num_accounts > MAX_CPI_INSTRUCTION_ACCOUNTS {
+            return fail(format!(
+                "Invoked an instruction with too many accounts ({} > {})",
+                num_accounts, MAX_CPI_INSTRUCTION_ACCOUNTS
+            ));
         }
-        InstructionError::DuplicateAccountIndex => {
-            Err(solana_sdk::instruction::InstructionError::DuplicateAccountIndex)
+        let max_account_infos = if feature_set.is_active(&increase_tx_account_lock_limit::id()) {
+            MAX_CPI_ACCOUNT_INFOS
+        } else {
+            64
+        };
+        if num_account_infos > max_account_infos {
+            return fail(format!(
+                "Invoked an instruction with too many account info's ({} > {})",
+                num_account_infos, max_account_infos
+            ));
         }
-        InstructionError::ExecutableModified => {
-            Err(solana_sdk::instruction::InstructionError::ExecutableModified)
+    } else {
+        let size = num_accounts
+            .saturating_mul(size_of::<AccountMeta>())
+            .saturating_add(data_len);
+        if size > max_cpi_instruction_size {
+            return fail(format!(
+                "Instruction passed to inner instruction is too large ({} > {})",
+                size, max_cpi_instruction_size
+            ));
         }
-        InstructionError::RentEpochModified => {
-            Err(solana_sdk::instruction::InstructionError::RentEpochModified)
+        if num_account_infos.saturating_mul(size_of::<Pubkey>()) > max_cpi_instruction_size {
+            return fail("Too many accounts passed to inner instruction".to_string());
         }
-        InstructionError::NotEnoughAccountKeys => Ok(ProgramError::NotEnoughAccountKeys),
-        InstructionError::AccountDataSizeChanged => {
-            Err(solana_sdk::instruction::InstructionError::AccountDataSizeChanged)
+    }
+    Ok(())
+}
+
+/// Rejects CPIs into the loaders, apart from the upgradeable loader instructions programs
+/// may invoke. Like an oversized CPI, this aborts the caller on chain.
+///
+/// The runtime rejects CPIs into precompiles as well, which the stubs verify instead.
+fn check_authorized_program(
+    invoke_context: &InvokeContext,
+    instruction: &Instruction,
+) -> Result<(), TridentStubError> {
+    let program_id = &instruction.program_id;
+    let data = &instruction.data;
+    if native_loader::check_id(program_id)
+        || bpf_loader::check_id(program_id)
+        || bpf_loader_deprecated::check_id(program_id)
+        || (bpf_loader_upgradeable::check_id(program_id)
+            && !(bpf_loader_upgradeable::is_upgrade_instruction(data)
+                || bpf_loader_upgradeable::is_set_authority_instruction(data)
+                || (invoke_context
+                    .get_feature_set()
+                    .is_active(&enable_bpf_loader_set_authority_checked_ix::id())
+                    && bpf_loader_upgradeable::is_set_authority_checked_instruction(data))
+                || bpf_loader_upgradeable::is_close_instruction(data)))
+    {
+        ic_msg!(
+            invoke_context,
+            "Program {} not supported by inner instructions",
+            program_id
+        );
+        return Err(TridentStubError::InvalidInvocation(
+            InstructionError::ProgramFailedToComplete,
+        ));
+    }
+    Ok(())
+}
+
+/// Checks that every account of the CPI has an AccountInfo whose lamports and data can be
+/// borrowed, so copying them to and from the callee cannot fail halfway.
+fn check_account_infos(
+    invoke_context: &InvokeContext,
+    instruction: &Instruction,
+    account_infos: &[AccountInfo],
+    account_info_indices: &HashMap<&Pubkey, usize>,
+) -> Result<(), TridentStubError> {
+    for account_meta in &instruction.accounts {
+        let Some(account_info) = account_info_indices
+            .get(&account_meta.pubkey)
+            .map(|index| &account_infos[*index])
+        else {
+            ic_msg!(
+                invoke_context,
+                "Instruction references an unknown account {}",
+                account_meta.pubkey
+            );
+            return Err(TridentStubError::AccountNotProvided {
+                pubkey: account_meta.pubkey,
+            });
+        };
+
+        let borrowable = if account_meta.is_writable {
+            account_info.try_borrow_mut_lamports().is_ok()
+                && account_info.try_borrow_mut_data().is_ok()
+        } else {
+            account_info.try_borrow_lamports().is_ok() && account_info.try_borrow_data().is_ok()
+        };
+        if !borrowable {
+            ic_msg!(
+                invoke_context,
+                "Account {} is already borrowed by the caller",
+                account_meta.pubkey
+            );
+            return Err(TridentStubError::AccountBorrowed {
+                pubkey: account_meta.pubkey,
+            });
         }
-        InstructionError::AccountNotExecutable => {
-            Err(solana_sdk::instruction::InstructionError::AccountNotExecutable)
+    }
+    Ok(())
+}
+
+/// Mirrors `InvokeContext::prepare_instruction`, but borrows the caller's `Instruction`
+/// instead of requiring an owned `StableInstruction`, and resolves the callee program from
+/// the transaction accounts, so builtins such as the System Program can be invoked without
+/// the caller receiving the program account.
+///
+/// This is more lenient than the runtime, which fails the CPI with `MissingAccount` unless
+/// the program account is one of the caller's instruction accounts. A CPI that succeeds
+/// here may therefore fail on chain if the client does not pass the callee program.
+#[allow(clippy::type_complexity)]
+fn prepare_instruction(
+    invoke_context: &InvokeContext,
+    instruction: &Instruction,
+    signers: &[Pubkey],
+) -> Result<(Vec<InstructionAccount>, Vec<IndexOfAccount>), InstructionError> {
+    let transaction_context = &invoke_context.transaction_context;
+    let instruction_context = transaction_context.get_current_instruction_context()?;
+
+    // Finds the index of each account in the instruction by its pubkey.
+    // Then normalizes / unifies the privileges of duplicate accounts.
+    let mut deduplicated_instruction_accounts: Vec<InstructionAccount> = Vec::new();
+    let mut duplicate_indices = Vec::with_capacity(instruction.accounts.len());
+    for (instruction_account_index, account_meta) in instruction.accounts.iter().enumerate() {
+        let index_in_transaction = transaction_context
+            .find_index_of_account(&account_meta.pubkey)
+            .ok_or_else(|| {
+                ic_msg!(
+                    invoke_context,
+                    "Instruction references an unknown account {}",
+                    account_meta.pubkey,
+                );
+                InstructionError::MissingAccount
+            })?;
+        if let Some(duplicate_index) =
+            deduplicated_instruction_accounts
+                .iter()
+                .position(|instruction_account| {
+                    instruction_account.index_in_transaction == index_in_transaction
+                })
+        {
+            duplicate_indices.push(duplicate_index);
+            let instruction_account = &mut deduplicated_instruction_accounts[duplicate_index];
+            instruction_account.is_signer |= account_meta.is_signer;
+            instruction_account.is_writable |= account_meta.is_writable;
+        } else {
+            let index_in_caller = instruction_context
+                .find_index_of_instruction_account(transaction_context, &account_meta.pubkey)
+                .ok_or_else(|| {
+                    ic_msg!(
+                        invoke_context,
+                        "Instruction references an unknown account {}",
+                        account_meta.pubkey,
+                    );
+                    InstructionError::MissingAccount
+                })?;
+            duplicate_indices.push(deduplicated_instruction_accounts.len());
+            deduplicated_instruction_accounts.push(InstructionAccount {
+                index_in_transaction,
+                index_in_caller,
+                index_in_callee: instruction_account_index as IndexOfAccount,
+                is_signer: account_meta.is_signer,
+                is_writable: account_meta.is_writable,
+            });
         }
-        InstructionError::AccountBorrowFailed => Ok(ProgramError::AccountBorrowFailed),
-        InstructionError::AccountBorrowOutstanding => {
-            Err(solana_sdk::instruction::InstructionError::AccountBorrowOutstanding)
+    }
+
+    for instruction_account in deduplicated_instruction_accounts.iter() {
+        let borrowed_account = instruction_context.try_borrow_instruction_account(
+            transaction_context,
+            instruction_account.index_in_caller,
+        )?;
+
+        // Readonly in caller cannot become writable in callee
+        if instruction_account.is_writable && !borrowed_account.is_writable() {
+            ic_msg!(
+                invoke_context,
+                "{}'s writable privilege escalated",
+                borrowed_account.get_key(),
+            );
+            return Err(InstructionError::PrivilegeEscalation);
         }
-        InstructionError::DuplicateAccountOutOfSync => {
-            Err(solana_sdk::instruction::InstructionError::DuplicateAccountOutOfSync)
+
+        // To be signed in the callee,
+        // it must be either signed in the caller or by the program
+        if instruction_account.is_signer
+            && !(borrowed_account.is_signer() || signers.contains(borrowed_account.get_key()))
+        {
+            ic_msg!(
+                invoke_context,
+                "{}'s signer privilege escalated",
+                borrowed_account.get_key()
+            );
+            return Err(InstructionError::PrivilegeEscalation);
         }
-        InstructionError::Custom(num) => Ok(ProgramError::Custom(num)),
-        InstructionError::InvalidError => {
-            Err(solana_sdk::instruction::InstructionError::InvalidError)
+    }
+
+    let instruction_accounts = duplicate_indices
+        .into_iter()
+        .map(|duplicate_index| deduplicated_instruction_accounts[duplicate_index].clone())
+        .collect();
+
+    // Unlike on chain, the callee does not have to be one of the caller's accounts, only
+    // loaded by the transaction
+    let callee_program_id = instruction.program_id;
+    let program_account_index = transaction_context
+        .find_index_of_program_account(&callee_program_id)
+        .ok_or_else(|| {
+            ic_msg!(invoke_context, "Unknown program {}", callee_program_id);
+            InstructionError::MissingAccount
+        })?;
+    let program_account = transaction_context
+        .get_account_at_index(program_account_index)?
+        .try_borrow()
+        .map_err(|_| InstructionError::AccountBorrowFailed)?;
+    if !program_account.executable() {
+        ic_msg!(
+            invoke_context,
+            "Account {} is not executable",
+            callee_program_id
+        );
+        return Err(InstructionError::AccountNotExecutable);
+    }
+
+    // The program cache resolves upgradeable programs, so unlike before 2.0 their
+    // ProgramData account is not part of the program accounts
+    Ok((instruction_accounts, vec![program_account_index]))
+}
+
+/// Emits `message` as a `log` record targeted at the program executing in the current invoke
+/// context.
+fn forward_log(message: &str) {
+    let Some(invoke_context) = try_get_invoke_context() else {
+        return;
+    };
+    let transaction_context = &invoke_context.transaction_context;
+    let Ok(program_id) = transaction_context
+        .get_current_instruction_context()
+        .and_then(|instruction_context| {
+            instruction_context.get_last_program_key(transaction_context)
+        })
+    else {
+        return;
+    };
+    log::info!(
+        target: &format!("trident::program::{}", program_id),
+        "[{}] {}",
+        invoke_context.get_stack_height(),
+        message
+    );
+}
+
+/// Logs the program id, the resolved account metas and the hex-encoded data of a CPI.
+fn dump_instruction(
+    invoke_context: &InvokeContext,
+    instruction: &Instruction,
+    instruction_accounts: &[InstructionAccount],
+    max_data_len: usize,
+) {
+    let transaction_context = &invoke_context.transaction_context;
+    ic_msg!(invoke_context, "CPI to {}", instruction.program_id);
+    for (index, instruction_account) in instruction_accounts.iter().enumerate() {
+        let Ok(pubkey) = transaction_context
+            .get_key_of_account_at_index(instruction_account.index_in_transaction)
+        else {
+            continue;
+        };
+        ic_msg!(
+            invoke_context,
+            "CPI account {}: {} signer={} writable={}",
+            index,
+            pubkey,
+            instruction_account.is_signer,
+            instruction_account.is_writable
+        );
+    }
+    let data = &instruction.data[..instruction.data.len().min(max_data_len)];
+    let hex = data
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<String>();
+    let ellipsis = if data.len() < instruction.data.len() {
+        "..."
+    } else {
+        ""
+    };
+    ic_msg!(
+        invoke_context,
+        "CPI data ({} bytes): {}{}",
+        instruction.data.len(),
+        hex,
+        ellipsis
+    );
+}
+
+/// Charges for a memory syscall. Like sysvar reads, memory syscalls made when no program is
+/// executing, e.g. by test code, are free.
+fn mem_op_consume(n: usize) {
+    let Some(invoke_context) = try_get_invoke_context() else {
+        return;
+    };
+    let compute_budget = invoke_context.get_compute_budget();
+    let cost = compute_budget.mem_op_base_cost.max(
+        (n as u64)
+            .checked_div(compute_budget.cpi_bytes_per_unit)
+            .unwrap_or(u64::MAX),
+    );
+
+    if let Err(err) = invoke_context.consume_checked(cost) {
+        panic!("{err}");
+    }
+}
+
+/// Charges for a sysvar read. Sysvars are served without charge when no program is
+/// executing.
+fn sysvar_consume(cost: impl FnOnce(&ComputeBudget) -> u64) {
+    if let Some(invoke_context) = try_get_invoke_context() {
+        let cost = cost(invoke_context.get_compute_budget());
+        if let Err(err) = invoke_context.consume_checked(cost) {
+            panic!("{err}");
         }
-        InstructionError::ExecutableDataModified => {
-            Err(solana_sdk::instruction::InstructionError::ExecutableDataModified)
+    }
+}
+
+fn get_sysvar<T: Sysvar + Clone + 'static>(
+    load: fn(&SysvarCache) -> Result<Arc<T>, InstructionError>,
+    var_addr: *mut u8,
+) -> u64 {
+    record(|| SyscallEvent::GetSysvar { sysvar_id: T::id() });
+    if inject_sysvar_fault(&T::id()) {
+        return UNSUPPORTED_SYSVAR;
+    }
+    sysvar_consume(|compute_budget| {
+        compute_budget
+            .sysvar_base_cost
+            .saturating_add(size_of::<T>() as u64)
+    });
+
+    match current_sysvar(load) {
+        // The destination may be unaligned and uninitialized, so write without dropping
+        Ok(sysvar) => unsafe {
+            std::ptr::write_unaligned(var_addr as *mut T, sysvar);
+            SUCCESS
+        },
+        Err(_) => UNSUPPORTED_SYSVAR,
+    }
+}
+
+/// Converts an `InstructionError` into the `ProgramError` a program observes.
+///
+/// The conversion is lossy: errors without a `ProgramError` counterpart, which the runtime
+/// reports by failing the whole transaction, are all returned as
+/// `ProgramError::InvalidArgument`. The original error of a failed CPI remains available
+/// through `take_last_cpi_error`.
+pub fn instruction_error_to_program_error(error: InstructionError) -> ProgramError {
+    match error {
+        InstructionError::InvalidArgument => ProgramError::InvalidArgument,
+        InstructionError::InvalidInstructionData => ProgramError::InvalidInstructionData,
+        InstructionError::InvalidAccountData => ProgramError::InvalidAccountData,
+        InstructionError::AccountDataTooSmall => ProgramError::AccountDataTooSmall,
+        InstructionError::InsufficientFunds => ProgramError::InsufficientFunds,
+        InstructionError::IncorrectProgramId => ProgramError::IncorrectProgramId,
+        InstructionError::MissingRequiredSignature => ProgramError::MissingRequiredSignature,
+        InstructionError::AccountAlreadyInitialized => ProgramError::AccountAlreadyInitialized,
+        InstructionError::UninitializedAccount => ProgramError::UninitializedAccount,
+        InstructionError::NotEnoughAccountKeys => ProgramError::NotEnoughAccountKeys,
+        InstructionError::AccountBorrowFailed => ProgramError::AccountBorrowFailed,
+        InstructionError::Custom(num) => ProgramError::Custom(num),
+        InstructionError::MaxSeedLengthExceeded => ProgramError::MaxSeedLengthExceeded,
+        InstructionError::InvalidSeeds => ProgramError::InvalidSeeds,
+        InstructionError::InvalidRealloc => ProgramError::InvalidRealloc,
+        InstructionError::BorshIoError(x) => ProgramError::BorshIoError(x),
+        InstructionError::AccountNotRentExempt => ProgramError::AccountNotRentExempt,
+        InstructionError::InvalidAccountOwner => ProgramError::InvalidAccountOwner,
+        InstructionError::ArithmeticOverflow => ProgramError::ArithmeticOverflow,
+        InstructionError::UnsupportedSysvar => ProgramError::UnsupportedSysvar,
+        InstructionError::IllegalOwner => ProgramError::IllegalOwner,
+        InstructionError::MaxAccountsDataAllocationsExceeded => {
+            ProgramError::MaxAccountsDataAllocationsExceeded
         }
-        InstructionError::ExecutableLamportChange => {
-            Err(solana_sdk::instruction::InstructionError::ExecutableLamportChange)
+        InstructionError::MaxInstructionTraceLengthExceeded => {
+            ProgramError::MaxInstructionTraceLengthExceeded
         }
-        InstructionError::ExecutableAccountNotRentExempt => {
-            Err(solana_sdk::instruction::InstructionError::ExecutableAccountNotRentExempt)
+        InstructionError::BuiltinProgramsMustConsumeComputeUnits => {
+            ProgramError::BuiltinProgramsMustConsumeComputeUnits
         }
-        InstructionError::UnsupportedProgramId => {
-            Err(solana_sdk::instruction::InstructionError::UnsupportedProgramId)
+        InstructionError::Immutable => ProgramError::Immutable,
+        InstructionError::IncorrectAuthority => ProgramError::IncorrectAuthority,
+        InstructionError::GenericError
+        | InstructionError::UnbalancedInstruction
+        | InstructionError::ModifiedProgramId
+        | InstructionError::ExternalAccountLamportSpend
+        | InstructionError::ExternalAccountDataModified
+        | InstructionError::ReadonlyLamportChange
+        | InstructionError::ReadonlyDataModified
+        | InstructionError::DuplicateAccountIndex
+        | InstructionError::ExecutableModified
+        | InstructionError::RentEpochModified
+        | InstructionError::AccountDataSizeChanged
+        | InstructionError::AccountNotExecutable
+        | InstructionError::AccountBorrowOutstanding
+        | InstructionError::DuplicateAccountOutOfSync
+        | InstructionError::InvalidError
+        | InstructionError::ExecutableDataModified
+        | InstructionError::ExecutableLamportChange
+        | InstructionError::ExecutableAccountNotRentExempt
+        | InstructionError::UnsupportedProgramId
+        | InstructionError::CallDepth
+        | InstructionError::MissingAccount
+        | InstructionError::ReentrancyNotAllowed
+        | InstructionError::ComputationalBudgetExceeded
+        | InstructionError::PrivilegeEscalation
+        | InstructionError::ProgramEnvironmentSetupFailure
+        | InstructionError::ProgramFailedToComplete
+        | InstructionError::ProgramFailedToCompile
+        | InstructionError::MaxAccountsExceeded => ProgramError::InvalidArgument,
+    }
+}
+
+/// Converts a `ProgramError` back into the `InstructionError` the runtime records for it.
+///
+/// Unlike the runtime, which goes through the `u64` error code, this keeps the message of
+/// `BorshIoError`.
+pub fn program_error_to_instruction_error(error: &ProgramError) -> InstructionError {
+    match error {
+        ProgramError::Custom(num) => InstructionError::Custom(*num),
+        ProgramError::InvalidArgument => InstructionError::InvalidArgument,
+        ProgramError::InvalidInstructionData => InstructionError::InvalidInstructionData,
+        ProgramError::InvalidAccountData => InstructionError::InvalidAccountData,
+        ProgramError::AccountDataTooSmall => InstructionError::AccountDataTooSmall,
+        ProgramError::InsufficientFunds => InstructionError::InsufficientFunds,
+        ProgramError::IncorrectProgramId => InstructionError::IncorrectProgramId,
+        ProgramError::MissingRequiredSignature => InstructionError::MissingRequiredSignature,
+        ProgramError::AccountAlreadyInitialized => InstructionError::AccountAlreadyInitialized,
+        ProgramError::UninitializedAccount => InstructionError::UninitializedAccount,
+        ProgramError::NotEnoughAccountKeys => InstructionError::NotEnoughAccountKeys,
+        ProgramError::AccountBorrowFailed => InstructionError::AccountBorrowFailed,
+        ProgramError::MaxSeedLengthExceeded => InstructionError::MaxSeedLengthExceeded,
+        ProgramError::InvalidSeeds => InstructionError::InvalidSeeds,
+        ProgramError::BorshIoError(x) => InstructionError::BorshIoError(x.clone()),
+        ProgramError::AccountNotRentExempt => InstructionError::AccountNotRentExempt,
+        ProgramError::UnsupportedSysvar => InstructionError::UnsupportedSysvar,
+        ProgramError::IllegalOwner => InstructionError::IllegalOwner,
+        ProgramError::MaxAccountsDataAllocationsExceeded => {
+            InstructionError::MaxAccountsDataAllocationsExceeded
         }
-        InstructionError::CallDepth => Err(solana_sdk::instruction::InstructionError::CallDepth),
-        InstructionError::MissingAccount => {
-            Err(solana_sdk::instruction::InstructionError::MissingAccount)
+        ProgramError::InvalidRealloc => InstructionError::InvalidRealloc,
+        ProgramError::MaxInstructionTraceLengthExceeded => {
+            InstructionError::MaxInstructionTraceLengthExceeded
         }
-        InstructionError::ReentrancyNotAllowed => {
-            Err(solana_sdk::instruction::InstructionError::ReentrancyNotAllowed)
+        ProgramError::BuiltinProgramsMustConsumeComputeUnits => {
+            InstructionError::BuiltinProgramsMustConsumeComputeUnits
         }
-        InstructionError::MaxSeedLengthExceeded => Ok(ProgramError::MaxSeedLengthExceeded),
-        InstructionError::InvalidSeeds => Ok(ProgramError::InvalidSeeds),
-        InstructionError::InvalidRealloc => Ok(ProgramError::InvalidRealloc),
-        InstructionError::ComputationalBudgetExceeded => {
-            Err(solana_sdk::instruction::InstructionError::ComputationalBudgetExceeded)
+        ProgramError::InvalidAccountOwner => InstructionError::InvalidAccountOwner,
+        ProgramError::ArithmeticOverflow => InstructionError::ArithmeticOverflow,
+        ProgramError::Immutable => InstructionError::Immutable,
+        ProgramError::IncorrectAuthority => InstructionError::IncorrectAuthority,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::activate_feature;
+    use crate::deactivate_feature;
+    use crate::set_feature_set;
+    use crate::take_cpi_trace;
+    use crate::take_last_cpi_error;
+    use crate::take_program_logs;
+    use crate::test_utils::account_infos;
+    use crate::test_utils::serialize_instruction_accounts;
+    use crate::test_utils::transaction_account;
+    use crate::test_utils::with_mock_caller;
+    use crate::test_utils::CALLER_PROGRAM_ID;
+
+    use std::cell::RefCell;
+    use std::sync::Mutex;
+
+    use solana_sdk::account::create_account_shared_data_for_test;
+    use solana_sdk::account::AccountSharedData;
+    use solana_sdk::account::WritableAccount;
+    use solana_sdk::bpf_loader_upgradeable::UpgradeableLoaderState;
+    use solana_sdk::feature_set::FeatureSet;
+    use solana_sdk::fee_calculator::FeeCalculator;
+    use solana_sdk::hash::Hash;
+    use solana_sdk::loader_upgradeable_instruction::UpgradeableLoaderInstruction;
+    use solana_sdk::program_stubs::SyscallStubs;
+    use solana_sdk::rent::Rent;
+    use solana_sdk::slot_hashes::SlotHashes;
+    use solana_sdk::stake_history::StakeHistory;
+    use solana_sdk::stake_history::StakeHistoryEntry;
+    use solana_sdk::system_program;
+    #[allow(deprecated)]
+    use solana_sdk::sysvar::fees::Fees;
+    use solana_sdk::sysvar::slot_hashes::SlotHashesSysvar;
+
+    use solana_program_runtime::declare_process_instruction;
+
+    const CALLEE_ID: Pubkey = Pubkey::new_from_array([2; 32]);
+
+    declare_process_instruction!(SetReturnData, 1, |invoke_context| {
+        let transaction_context = &mut invoke_context.transaction_context;
+        let data = transaction_context
+            .get_current_instruction_context()?
+            .get_instruction_data()
+            .to_vec();
+        transaction_context.set_return_data(CALLEE_ID, data)
+    });
+
+    declare_process_instruction!(WriteData, 1, |invoke_context| {
+        let transaction_context = &invoke_context.transaction_context;
+        let instruction_context = transaction_context.get_current_instruction_context()?;
+        let mut account =
+            instruction_context.try_borrow_instruction_account(transaction_context, 0)?;
+        account.set_data_from_slice(instruction_context.get_instruction_data())
+    });
+
+    // Resizes every account to the length in the instruction data and fills it with ones
+    declare_process_instruction!(ResizeData, 1, |invoke_context| {
+        let transaction_context = &invoke_context.transaction_context;
+        let instruction_context = transaction_context.get_current_instruction_context()?;
+        let new_len = instruction_context
+            .get_instruction_data()
+            .try_into()
+            .map(u64::from_le_bytes)
+            .map_err(|_| InstructionError::InvalidInstructionData)?;
+        for index in 0..instruction_context.get_number_of_instruction_accounts() {
+            let mut account =
+                instruction_context.try_borrow_instruction_account(transaction_context, index)?;
+            account.set_data_length(new_len as usize)?;
+            account.get_data_mut()?.fill(1);
         }
-        InstructionError::PrivilegeEscalation => {
-            Err(solana_sdk::instruction::InstructionError::PrivilegeEscalation)
+        Ok(())
+    });
+
+    // Fails with the InstructionError serialized as JSON in the instruction data
+    declare_process_instruction!(Fail, 1, |invoke_context| {
+        let error = serde_json::from_slice(
+            invoke_context
+                .transaction_context
+                .get_current_instruction_context()?
+                .get_instruction_data(),
+        )
+        .map_err(|_| InstructionError::InvalidInstructionData)?;
+        Err(error)
+    });
+
+    // Allocates eight bytes to account 0 and assigns it to the owner in the instruction data,
+    // like the System Program's CreateAccount
+    declare_process_instruction!(CreateAccount, 1, |invoke_context| {
+        let transaction_context = &invoke_context.transaction_context;
+        let instruction_context = transaction_context.get_current_instruction_context()?;
+        let mut account =
+            instruction_context.try_borrow_instruction_account(transaction_context, 0)?;
+        account.set_data_length(8)?;
+        account.set_owner(instruction_context.get_instruction_data())
+    });
+
+    thread_local! {
+        static CAPTURED_LOGS: RefCell<Vec<(String, String)>> = const { RefCell::new(Vec::new()) };
+    }
+
+    /// Records the target and message of every `log` record made on the current thread.
+    struct CapturingLogger;
+
+    impl log::Log for CapturingLogger {
+        fn enabled(&self, _metadata: &log::Metadata) -> bool {
+            true
         }
-        InstructionError::ProgramEnvironmentSetupFailure => {
-            Err(solana_sdk::instruction::InstructionError::ProgramEnvironmentSetupFailure)
+
+        fn log(&self, record: &log::Record) {
+            CAPTURED_LOGS.with(|logs| {
+                logs.borrow_mut()
+                    .push((record.target().to_string(), record.args().to_string()))
+            });
         }
-        InstructionError::ProgramFailedToComplete => {
-            Err(solana_sdk::instruction::InstructionError::ProgramFailedToComplete)
+
+        fn flush(&self) {}
+    }
+
+    static CAPTURING_LOGGER: CapturingLogger = CapturingLogger;
+
+    /// Returns an account owned by the callee, so it can write the account's data.
+    fn callee_account(data: &[u8]) -> (Pubkey, AccountSharedData) {
+        let mut account = AccountSharedData::new(1_000_000, data.len(), &CALLEE_ID);
+        account.set_data_from_slice(data);
+        (Pubkey::new_unique(), account)
+    }
+
+    fn get_sysvar_slice(sysvar_id: &Pubkey, offset: u64, length: u64) -> (u64, Vec<u8>) {
+        let mut buffer = vec![0; length as usize];
+        let result = TridentSyscallStubs::default().sol_get_sysvar(
+            sysvar_id.as_ref().as_ptr(),
+            buffer.as_mut_ptr(),
+            offset,
+            length,
+        );
+        (result, buffer)
+    }
+
+    #[test]
+    fn sol_get_sysvar_copies_the_requested_range() {
+        let clock = Clock {
+            slot: 7,
+            epoch_start_timestamp: 11,
+            epoch: 3,
+            leader_schedule_epoch: 4,
+            unix_timestamp: 42,
+        };
+        let clock_account = create_account_shared_data_for_test(&clock);
+        let serialized = clock_account.data().to_vec();
+
+        with_mock_caller(vec![(Clock::id(), clock_account)], &[], || {
+            let length = serialized.len() as u64;
+            assert_eq!(
+                get_sysvar_slice(&Clock::id(), 0, length),
+                (SUCCESS, serialized.clone())
+            );
+            assert_eq!(
+                get_sysvar_slice(&Clock::id(), 16, 8),
+                (SUCCESS, 3u64.to_le_bytes().to_vec())
+            );
+            assert_eq!(
+                get_sysvar_slice(&Clock::id(), length, 0),
+                (SUCCESS, Vec::new())
+            );
+            assert_eq!(
+                get_sysvar_slice(&Clock::id(), 1, length).0,
+                OFFSET_LENGTH_EXCEEDS_SYSVAR
+            );
+            assert_eq!(
+                get_sysvar_slice(&Pubkey::new_unique(), 0, 8).0,
+                SYSVAR_NOT_FOUND
+            );
+        });
+    }
+
+    #[test]
+    fn sol_get_sysvar_serves_stake_history_in_chunks() {
+        let mut stake_history = StakeHistory::default();
+        for epoch in 1..=3 {
+            stake_history.add(
+                epoch,
+                StakeHistoryEntry {
+                    effective: epoch * 100,
+                    activating: epoch * 10,
+                    deactivating: epoch,
+                },
+            );
         }
-        InstructionError::ProgramFailedToCompile => {
-            Err(solana_sdk::instruction::InstructionError::ProgramFailedToCompile)
+        let stake_history_account = create_account_shared_data_for_test(&stake_history);
+        let serialized = stake_history_account.data().to_vec();
+        let length = serialized.len() as u64;
+
+        with_mock_caller(
+            vec![(StakeHistory::id(), stake_history_account)],
+            &[],
+            || {
+                let mut chunks = Vec::new();
+                for offset in (0..length).step_by(1000) {
+                    let chunk_length = 1000.min(length - offset);
+                    let (result, chunk) =
+                        get_sysvar_slice(&StakeHistory::id(), offset, chunk_length);
+                    assert_eq!(result, SUCCESS);
+                    chunks.extend(chunk);
+                }
+                assert_eq!(chunks, serialized);
+
+                assert_eq!(
+                    get_sysvar_slice(&StakeHistory::id(), length, 1).0,
+                    OFFSET_LENGTH_EXCEEDS_SYSVAR
+                );
+                assert_eq!(
+                    get_sysvar_slice(&StakeHistory::id(), u64::MAX, 1).0,
+                    OFFSET_LENGTH_EXCEEDS_SYSVAR
+                );
+            },
+        );
+
+        with_mock_caller(Vec::new(), &[], || {
+            assert_eq!(
+                get_sysvar_slice(&StakeHistory::id(), 0, 8).0,
+                SYSVAR_NOT_FOUND
+            );
+        });
+    }
+
+    #[test]
+    fn slot_hashes_sysvar_reads_through_sol_get_sysvar() {
+        set_stubs_v2();
+        let slot_hashes = SlotHashes::new(&[(5, Hash::new_unique()), (3, Hash::new_unique())]);
+        let slot_hashes_account = create_account_shared_data_for_test(&slot_hashes);
+
+        with_mock_caller(vec![(SlotHashes::id(), slot_hashes_account)], &[], || {
+            assert_eq!(SlotHashesSysvar::get(&5), Ok(slot_hashes.get(&5).copied()));
+            assert_eq!(SlotHashesSysvar::get(&4), Ok(None));
+        });
+
+        with_mock_caller(Vec::new(), &[], || {
+            assert!(SlotHashesSysvar::get(&5).is_err());
+        });
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn fees_sysvar_is_unsupported_once_disabled() {
+        set_stubs_v2();
+        let fees = Fees::new(&FeeCalculator::new(5_000));
+        let accounts = vec![(Fees::id(), create_account_shared_data_for_test(&fees))];
+
+        with_mock_caller(accounts, &[], || {
+            set_feature_set(FeatureSet::default());
+            assert_eq!(Fees::get(), Ok(fees));
+
+            activate_feature(&disable_fees_sysvar::id());
+            assert_eq!(Fees::get(), Err(ProgramError::UnsupportedSysvar));
+        });
+    }
+
+    #[test]
+    fn builder_hooks_intercept_their_syscalls() {
+        let logs = Arc::new(Mutex::new(Vec::new()));
+        let sink_logs = Arc::clone(&logs);
+        let stubs = TridentSyscallStubs::builder()
+            .with_clock(|| Clock {
+                slot: 7,
+                ..Clock::default()
+            })
+            .with_invoke_hook(|instruction, _account_infos| {
+                (instruction.data == [0]).then_some(Err(ProgramError::Custom(7)))
+            })
+            .with_log_sink(move |message| sink_logs.lock().unwrap().push(message.to_string()))
+            .build();
+
+        with_mock_caller(Vec::new(), &[(CALLEE_ID, SetReturnData::vm)], || {
+            let mut clock = std::mem::MaybeUninit::<Clock>::uninit();
+            assert_eq!(
+                stubs.sol_get_clock_sysvar(clock.as_mut_ptr() as *mut u8),
+                SUCCESS
+            );
+            assert_eq!(unsafe { clock.assume_init() }.slot, 7);
+
+            let invoke = |data: &[u8]| {
+                let instruction = Instruction::new_with_bytes(CALLEE_ID, data, Vec::new());
+                stubs.sol_invoke_signed(&instruction, &[], &[])
+            };
+            assert_eq!(invoke(&[0]), Err(ProgramError::Custom(7)));
+            assert_eq!(stubs.sol_get_return_data(), None);
+            assert_eq!(invoke(&[1]), Ok(()));
+            assert_eq!(stubs.sol_get_return_data(), Some((CALLEE_ID, vec![1])));
+
+            stubs.sol_log("message");
+            assert_eq!(*logs.lock().unwrap(), ["message"]);
+            assert!(!take_program_logs().contains(&"Program log: message".to_string()));
+        });
+    }
+
+    #[test]
+    fn cpi_dump_logs_the_callee_accounts_and_data() {
+        let (pubkey, account) = callee_account(&[]);
+        with_mock_caller(
+            vec![(pubkey, account)],
+            &[(CALLEE_ID, WriteData::vm)],
+            || {
+                let mut input = serialize_instruction_accounts();
+                let account_infos = account_infos(&mut input);
+                let instruction = Instruction::new_with_bytes(
+                    CALLEE_ID,
+                    &[0xab, 0xcd, 0xef],
+                    vec![AccountMeta::new(pubkey, false)],
+                );
+                let stubs = TridentSyscallStubs::builder().with_cpi_dump(2).build();
+                take_program_logs();
+
+                assert_eq!(
+                    stubs.sol_invoke_signed(&instruction, &account_infos, &[]),
+                    Ok(())
+                );
+                let logs = take_program_logs();
+                assert_eq!(
+                    logs[..3],
+                    [
+                        format!("CPI to {}", CALLEE_ID),
+                        format!("CPI account 0: {} signer=false writable=true", pubkey),
+                        "CPI data (3 bytes): abcd...".to_string(),
+                    ]
+                );
+            },
+        );
+    }
+
+    #[test]
+    fn log_forwarding_emits_log_records_besides_the_program_logs() {
+        // Only this test installs a logger, and records are captured per thread
+        let _ = log::set_logger(&CAPTURING_LOGGER);
+        log::set_max_level(log::LevelFilter::Info);
+        let stubs = TridentSyscallStubs::builder().with_log_forwarding().build();
+
+        with_mock_caller(Vec::new(), &[], || {
+            take_program_logs();
+            stubs.sol_log("message");
+            stubs.sol_log_data(&[b"ab"]);
+
+            assert_eq!(
+                take_program_logs(),
+                ["Program log: message", "Program data: YWI="]
+            );
+            let target = format!("trident::program::{}", CALLER_PROGRAM_ID);
+            assert_eq!(
+                CAPTURED_LOGS.with(RefCell::take),
+                [
+                    (target.clone(), "[1] Program log: message".to_string()),
+                    (target, "[1] Program data: YWI=".to_string()),
+                ]
+            );
+        });
+    }
+
+    #[test]
+    fn sol_log_compute_units_logs_the_remaining_units() {
+        with_mock_caller(Vec::new(), &[], || {
+            let invoke_context = get_invoke_context();
+            invoke_context.mock_set_remaining(1_000);
+            invoke_context.consume(100);
+            take_program_logs();
+
+            TridentSyscallStubs::default().sol_log_compute_units();
+            assert_eq!(invoke_context.get_remaining(), 900);
+            assert_eq!(
+                take_program_logs(),
+                [format!(
+                    "Program consumption: {} units remaining",
+                    invoke_context.get_remaining()
+                )]
+            );
+        });
+    }
+
+    #[test]
+    fn sol_invoke_signed_rejects_metas_without_a_borrowable_account_info() {
+        let (pubkey, account) = callee_account(&[]);
+        with_mock_caller(
+            vec![(pubkey, account)],
+            &[(CALLEE_ID, WriteData::vm)],
+            || {
+                let mut input = serialize_instruction_accounts();
+                let account_infos = account_infos(&mut input);
+                let stubs = TridentSyscallStubs::default();
+                let invoke = |account_meta, account_infos: &[AccountInfo]| {
+                    let instruction =
+                        Instruction::new_with_bytes(CALLEE_ID, &[], vec![account_meta]);
+                    stubs.sol_invoke_signed(&instruction, account_infos, &[])
+                };
+
+                assert_eq!(
+                    invoke(AccountMeta::new(pubkey, false), &[]),
+                    Err(ProgramError::InvalidArgument)
+                );
+                assert_eq!(
+                    take_last_cpi_error(),
+                    Some(TridentStubError::AccountNotProvided { pubkey })
+                );
+
+                // Writable accounts need mutable borrows, read-only ones shared borrows
+                let data = account_infos[0].try_borrow_data().unwrap();
+                assert_eq!(
+                    invoke(AccountMeta::new(pubkey, false), &account_infos),
+                    Err(ProgramError::AccountBorrowFailed)
+                );
+                assert_eq!(
+                    take_last_cpi_error(),
+                    Some(TridentStubError::AccountBorrowed { pubkey })
+                );
+                drop(data);
+                let lamports = account_infos[0].try_borrow_mut_lamports().unwrap();
+                assert_eq!(
+                    invoke(AccountMeta::new_readonly(pubkey, false), &account_infos),
+                    Err(ProgramError::AccountBorrowFailed)
+                );
+                assert_eq!(
+                    take_last_cpi_error(),
+                    Some(TridentStubError::AccountBorrowed { pubkey })
+                );
+                drop(lamports);
+
+                assert_eq!(
+                    take_program_logs()
+                        .into_iter()
+                        .filter(|log| log.contains(&pubkey.to_string()))
+                        .collect::<Vec<_>>(),
+                    [
+                        format!("Instruction references an unknown account {}", pubkey),
+                        format!("Account {} is already borrowed by the caller", pubkey),
+                        format!("Account {} is already borrowed by the caller", pubkey),
+                    ]
+                );
+            },
+        );
+    }
+
+    #[test]
+    fn sol_invoke_signed_rejects_accounts_the_caller_was_not_passed() {
+        with_mock_caller(Vec::new(), &[(CALLEE_ID, WriteData::vm)], || {
+            // The callee program is loaded by the transaction, but no account of the caller
+            let mut lamports = 1;
+            let mut data = Vec::new();
+            let owner = Pubkey::default();
+            let account_info = AccountInfo::new(
+                &CALLEE_ID,
+                false,
+                true,
+                &mut lamports,
+                &mut data,
+                &owner,
+                true,
+                0,
+            );
+            let instruction = Instruction::new_with_bytes(
+                CALLEE_ID,
+                &[],
+                vec![AccountMeta::new(CALLEE_ID, false)],
+            );
+
+            assert_eq!(
+                TridentSyscallStubs::default().sol_invoke_signed(
+                    &instruction,
+                    &[account_info],
+                    &[]
+                ),
+                Err(ProgramError::InvalidArgument)
+            );
+            assert_eq!(
+                take_last_cpi_error(),
+                Some(TridentStubError::InvalidInvocation(
+                    InstructionError::MissingAccount
+                ))
+            );
+        });
+    }
+
+    #[test]
+    fn sol_invoke_signed_rejects_escalated_privileges_of_duplicate_metas() {
+        let (pubkey, account) = callee_account(&[]);
+        with_mock_caller(
+            vec![(pubkey, account)],
+            &[(CALLEE_ID, SetReturnData::vm)],
+            || {
+                // Pass the account to the caller as read-only and unsigned
+                let invoke_context = get_invoke_context();
+                invoke_context.pop().unwrap();
+                let transaction_context = &mut invoke_context.transaction_context;
+                let caller_index = transaction_context
+                    .find_index_of_account(&CALLER_PROGRAM_ID)
+                    .unwrap();
+                transaction_context
+                    .get_next_instruction_context()
+                    .unwrap()
+                    .configure(
+                        &[caller_index],
+                        &[InstructionAccount {
+                            index_in_transaction: 0,
+                            index_in_caller: 0,
+                            index_in_callee: 0,
+                            is_signer: false,
+                            is_writable: false,
+                        }],
+                        &[],
+                    );
+                invoke_context.push().unwrap();
+
+                let mut input = serialize_instruction_accounts();
+                let account_infos = account_infos(&mut input);
+                let stubs = TridentSyscallStubs::default();
+                let invoke = |accounts| {
+                    let instruction = Instruction::new_with_bytes(CALLEE_ID, &[], accounts);
+                    stubs.sol_invoke_signed(&instruction, &account_infos, &[])
+                };
+
+                assert_eq!(
+                    invoke(vec![
+                        AccountMeta::new_readonly(pubkey, false),
+                        AccountMeta::new_readonly(pubkey, false),
+                    ]),
+                    Ok(())
+                );
+                // Duplicates are merged, so one writable or signer meta escalates both
+                for accounts in [
+                    vec![
+                        AccountMeta::new_readonly(pubkey, false),
+                        AccountMeta::new(pubkey, false),
+                    ],
+                    vec![
+                        AccountMeta::new_readonly(pubkey, true),
+                        AccountMeta::new_readonly(pubkey, false),
+                    ],
+                ] {
+                    assert_eq!(invoke(accounts), Err(ProgramError::InvalidArgument));
+                    assert_eq!(
+                        take_last_cpi_error(),
+                        Some(TridentStubError::InvalidInvocation(
+                            InstructionError::PrivilegeEscalation
+                        ))
+                    );
+                }
+            },
+        );
+    }
+
+    #[test]
+    fn memory_syscalls_work_without_an_invoke_context() {
+        let stubs = TridentSyscallStubs::default();
+        let mut buffer = [1u8; 8];
+        let mut result = 0;
+        unsafe {
+            stubs.sol_memset(buffer.as_mut_ptr(), 7, 4);
+            stubs.sol_memcmp(buffer.as_ptr(), [7u8; 8].as_ptr(), 8, &mut result);
         }
-        InstructionError::Immutable => Err(solana_sdk::instruction::InstructionError::Immutable),
-        InstructionError::IncorrectAuthority => {
-            Err(solana_sdk::instruction::InstructionError::IncorrectAuthority)
+        assert_eq!(buffer, [7, 7, 7, 7, 1, 1, 1, 1]);
+        assert_eq!(result, -6);
+    }
+
+    #[test]
+    fn memory_syscalls_charge_per_byte() {
+        with_mock_caller(Vec::new(), &[], || {
+            let stubs = TridentSyscallStubs::default();
+            let compute_budget = *get_invoke_context().get_compute_budget();
+            let mut buffer = vec![0u8; 100 * compute_budget.cpi_bytes_per_unit as usize];
+
+            let remaining = get_invoke_context().get_remaining();
+            unsafe { stubs.sol_memset(buffer.as_mut_ptr(), 1, buffer.len()) };
+            assert_eq!(remaining - get_invoke_context().get_remaining(), 100);
+
+            let remaining = get_invoke_context().get_remaining();
+            unsafe { stubs.sol_memset(buffer.as_mut_ptr(), 1, 0) };
+            assert_eq!(
+                remaining - get_invoke_context().get_remaining(),
+                compute_budget.mem_op_base_cost
+            );
+        });
+    }
+
+    #[test]
+    fn sol_memcpy_accepts_adjacent_regions() {
+        let stubs = TridentSyscallStubs::default();
+        let mut buffer = [1u8, 2, 3, 4];
+        let ptr = buffer.as_mut_ptr();
+        unsafe {
+            stubs.sol_memcpy(ptr.add(2), ptr, 2);
+            stubs.sol_memcpy(ptr, ptr, 0);
         }
-        InstructionError::BorshIoError(x) => Ok(ProgramError::BorshIoError(x)),
-        InstructionError::AccountNotRentExempt => Ok(ProgramError::AccountNotRentExempt),
-        InstructionError::InvalidAccountOwner => Ok(ProgramError::InvalidAccountOwner),
-        InstructionError::ArithmeticOverflow => Ok(ProgramError::ArithmeticOverflow),
-        InstructionError::UnsupportedSysvar => Ok(ProgramError::UnsupportedSysvar),
-        InstructionError::IllegalOwner => Ok(ProgramError::IllegalOwner),
-        InstructionError::MaxAccountsDataAllocationsExceeded => {
-            Ok(ProgramError::MaxAccountsDataAllocationsExceeded)
+        assert_eq!(buffer, [1, 2, 1, 2]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Overlapping copy")]
+    fn sol_memcpy_rejects_overlapping_regions() {
+        let stubs = TridentSyscallStubs::default();
+        let mut buffer = [1u8, 2, 3, 4];
+        let ptr = buffer.as_mut_ptr();
+        unsafe { stubs.sol_memcpy(ptr.add(1), ptr, 2) };
+    }
+
+    #[test]
+    fn sol_memmove_handles_overlapping_regions() {
+        let stubs = TridentSyscallStubs::default();
+        let mut buffer = [1u8, 2, 3, 4];
+        let ptr = buffer.as_mut_ptr();
+        unsafe { stubs.sol_memmove(ptr.add(1), ptr, 3) };
+        assert_eq!(buffer, [1, 1, 2, 3]);
+    }
+
+    #[test]
+    fn sol_get_return_data_reports_data_set_by_a_cpi() {
+        with_mock_caller(Vec::new(), &[(CALLEE_ID, SetReturnData::vm)], || {
+            let stubs = TridentSyscallStubs::default();
+            assert_eq!(stubs.sol_get_return_data(), None);
+
+            stubs.sol_set_return_data(&[]);
+            assert_eq!(stubs.sol_get_return_data(), None);
+
+            stubs.sol_set_return_data(&[1, 2]);
+            assert_eq!(
+                stubs.sol_get_return_data(),
+                Some((CALLER_PROGRAM_ID, vec![1, 2]))
+            );
+
+            let instruction = Instruction::new_with_bytes(CALLEE_ID, &[3, 4, 5], Vec::new());
+            assert_eq!(stubs.sol_invoke_signed(&instruction, &[], &[]), Ok(()));
+            assert_eq!(
+                stubs.sol_get_return_data(),
+                Some((CALLEE_ID, vec![3, 4, 5]))
+            );
+        });
+    }
+
+    #[test]
+    fn sol_set_return_data_accepts_the_maximum_length() {
+        with_mock_caller(Vec::new(), &[], || {
+            let stubs = TridentSyscallStubs::default();
+            stubs.sol_set_return_data(&[1; MAX_RETURN_DATA]);
+            assert_eq!(
+                stubs.sol_get_return_data(),
+                Some((CALLER_PROGRAM_ID, vec![1; MAX_RETURN_DATA]))
+            );
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "Return data too large (1025 > 1024)")]
+    fn sol_set_return_data_rejects_longer_data() {
+        with_mock_caller(Vec::new(), &[], || {
+            TridentSyscallStubs::default().sol_set_return_data(&[1; MAX_RETURN_DATA + 1]);
+        });
+    }
+
+    #[test]
+    fn sol_invoke_signed_syncs_duplicate_account_infos() {
+        let (pubkey, account) = callee_account(&[0; 4]);
+
+        with_mock_caller(
+            vec![(pubkey, account)],
+            &[(CALLEE_ID, WriteData::vm)],
+            || {
+                let mut input = serialize_instruction_accounts();
+                let account_info = account_infos(&mut input).remove(0);
+                let account_infos = [account_info.clone(), account_info];
+                let instruction = Instruction::new_with_bytes(
+                    CALLEE_ID,
+                    &[1, 2, 3, 4, 5, 6],
+                    vec![
+                        AccountMeta::new(pubkey, false),
+                        AccountMeta::new_readonly(pubkey, false),
+                    ],
+                );
+
+                let result = TridentSyscallStubs::default().sol_invoke_signed(
+                    &instruction,
+                    &account_infos,
+                    &[],
+                );
+
+                assert_eq!(result, Ok(()));
+                for account_info in &account_infos {
+                    assert_eq!(*account_info.data.borrow(), [1, 2, 3, 4, 5, 6]);
+                }
+                assert_eq!(transaction_account(&pubkey).data(), [1, 2, 3, 4, 5, 6]);
+            },
+        );
+    }
+
+    #[test]
+    fn sol_invoke_signed_limits_account_growth() {
+        let (pubkey, account) = callee_account(&[]);
+        let (other_pubkey, other_account) = callee_account(&[0; 8]);
+        let accounts = vec![(pubkey, account), (other_pubkey, other_account)];
+        let metas = vec![
+            AccountMeta::new(other_pubkey, false),
+            AccountMeta::new(pubkey, false),
+        ];
+
+        let at_limit = MAX_PERMITTED_DATA_INCREASE as u64;
+        let instruction =
+            Instruction::new_with_bytes(CALLEE_ID, &at_limit.to_le_bytes(), metas.clone());
+        with_mock_caller(accounts.clone(), &[(CALLEE_ID, ResizeData::vm)], || {
+            let mut input = serialize_instruction_accounts();
+            let account_infos = account_infos(&mut input);
+            assert_eq!(
+                TridentSyscallStubs::default().sol_invoke_signed(&instruction, &account_infos, &[]),
+                Ok(())
+            );
+            assert_eq!(
+                *account_infos[0].data.borrow(),
+                [1; MAX_PERMITTED_DATA_INCREASE]
+            );
+            assert_eq!(
+                *account_infos[1].data.borrow(),
+                [1; MAX_PERMITTED_DATA_INCREASE]
+            );
+        });
+
+        let over_limit = at_limit + 1;
+        let instruction = Instruction::new_with_bytes(CALLEE_ID, &over_limit.to_le_bytes(), metas);
+        with_mock_caller(accounts, &[(CALLEE_ID, ResizeData::vm)], || {
+            let mut input = serialize_instruction_accounts();
+            let account_infos = account_infos(&mut input);
+            assert_eq!(
+                TridentSyscallStubs::default().sol_invoke_signed(&instruction, &account_infos, &[]),
+                Err(ProgramError::InvalidRealloc)
+            );
+            // The account that grew within the limit is not written back either
+            assert!(account_infos[0].data_is_empty());
+            assert_eq!(*account_infos[1].data.borrow(), [0; 8]);
+        });
+    }
+
+    #[test]
+    fn sol_invoke_signed_zeroes_truncated_account_data() {
+        let (pubkey, account) = callee_account(&[7; 8]);
+
+        with_mock_caller(
+            vec![(pubkey, account)],
+            &[(CALLEE_ID, ResizeData::vm)],
+            || {
+                let mut input = serialize_instruction_accounts();
+                let account_infos = account_infos(&mut input);
+                let instruction = Instruction::new_with_bytes(
+                    CALLEE_ID,
+                    &2u64.to_le_bytes(),
+                    vec![AccountMeta::new(pubkey, false)],
+                );
+
+                assert_eq!(
+                    TridentSyscallStubs::default().sol_invoke_signed(
+                        &instruction,
+                        &account_infos,
+                        &[]
+                    ),
+                    Ok(())
+                );
+                assert_eq!(*account_infos[0].data.borrow(), [1, 1]);
+
+                account_infos[0].realloc(8, false).unwrap();
+                assert_eq!(*account_infos[0].data.borrow(), [1, 1, 0, 0, 0, 0, 0, 0]);
+            },
+        );
+    }
+
+    #[test]
+    fn sol_invoke_signed_leaves_invoke_and_result_logs_to_the_runtime() {
+        with_mock_caller(Vec::new(), &[(CALLEE_ID, SetReturnData::vm)], || {
+            let instruction = Instruction::new_with_bytes(CALLEE_ID, &[], Vec::new());
+            assert_eq!(
+                TridentSyscallStubs::default().sol_invoke_signed(&instruction, &[], &[]),
+                Ok(())
+            );
+            assert_eq!(
+                take_program_logs(),
+                [
+                    format!("Program {} invoke [2]", CALLEE_ID),
+                    format!("Program {} success", CALLEE_ID),
+                ]
+            );
+        });
+    }
+
+    #[test]
+    fn sol_invoke_signed_passes_large_instruction_data_through() {
+        let (pubkey, account) = callee_account(&[]);
+        let data = (0..MAX_CPI_INSTRUCTION_DATA_LEN)
+            .map(|index| index as u8)
+            .collect::<Vec<_>>();
+
+        with_mock_caller(
+            vec![(pubkey, account)],
+            &[(CALLEE_ID, WriteData::vm)],
+            || {
+                let mut input = serialize_instruction_accounts();
+                let account_infos = account_infos(&mut input);
+                let instruction = Instruction::new_with_bytes(
+                    CALLEE_ID,
+                    &data,
+                    vec![AccountMeta::new(pubkey, false)],
+                );
+
+                assert_eq!(
+                    TridentSyscallStubs::default().sol_invoke_signed(
+                        &instruction,
+                        &account_infos,
+                        &[]
+                    ),
+                    Ok(())
+                );
+                assert_eq!(*account_infos[0].data.borrow(), data);
+            },
+        );
+    }
+
+    #[test]
+    fn sol_invoke_signed_copies_back_written_data_only() {
+        let (pubkey, account) = callee_account(&[1, 2, 3]);
+        with_mock_caller(
+            vec![(pubkey, account)],
+            &[(CALLEE_ID, WriteData::vm)],
+            || {
+                let mut input = serialize_instruction_accounts();
+                let account_infos = account_infos(&mut input);
+                let metas = vec![AccountMeta::new(pubkey, false)];
+                let stubs = TridentSyscallStubs::default();
+
+                // Rewriting the same bytes still counts as a write
+                let instruction = Instruction::new_with_bytes(CALLEE_ID, &[1, 2, 3], metas.clone());
+                assert_eq!(
+                    stubs.sol_invoke_signed(&instruction, &account_infos, &[]),
+                    Ok(())
+                );
+                assert_eq!(*account_infos[0].data.borrow(), [1, 2, 3]);
+
+                let instruction = Instruction::new_with_bytes(CALLEE_ID, &[4, 5], metas);
+                assert_eq!(
+                    stubs.sol_invoke_signed(&instruction, &account_infos, &[]),
+                    Ok(())
+                );
+                assert_eq!(*account_infos[0].data.borrow(), [4, 5]);
+            },
+        );
+
+        let (pubkey, account) = callee_account(&[1, 2, 3]);
+        with_mock_caller(
+            vec![(pubkey, account)],
+            &[(CALLEE_ID, SetReturnData::vm)],
+            || {
+                let mut input = serialize_instruction_accounts();
+                let account_infos = account_infos(&mut input);
+                let instruction = Instruction::new_with_bytes(
+                    CALLEE_ID,
+                    &[],
+                    vec![AccountMeta::new(pubkey, false)],
+                );
+                assert_eq!(
+                    TridentSyscallStubs::default().sol_invoke_signed(
+                        &instruction,
+                        &account_infos,
+                        &[]
+                    ),
+                    Ok(())
+                );
+                assert_eq!(*account_infos[0].data.borrow(), [1, 2, 3]);
+            },
+        );
+    }
+
+    #[test]
+    fn sysvar_getters_write_to_unaligned_uninitialized_memory() {
+        let clock = Clock {
+            slot: 7,
+            unix_timestamp: 42,
+            ..Clock::default()
+        };
+        let rent = Rent {
+            lamports_per_byte_year: 5,
+            ..Rent::default()
+        };
+        let accounts = vec![
+            (Clock::id(), create_account_shared_data_for_test(&clock)),
+            (Rent::id(), create_account_shared_data_for_test(&rent)),
+        ];
+
+        with_mock_caller(accounts, &[], || {
+            let stubs = TridentSyscallStubs::default();
+
+            let mut buffer = vec![std::mem::MaybeUninit::<u8>::uninit(); size_of::<Clock>() + 1];
+            let var_addr = unsafe { buffer.as_mut_ptr().add(1) } as *mut u8;
+            assert_eq!(stubs.sol_get_clock_sysvar(var_addr), SUCCESS);
+            assert_eq!(
+                unsafe { std::ptr::read_unaligned(var_addr as *const Clock) },
+                clock
+            );
+
+            let mut buffer = vec![std::mem::MaybeUninit::<u8>::uninit(); size_of::<Rent>() + 1];
+            let var_addr = unsafe { buffer.as_mut_ptr().add(1) } as *mut u8;
+            assert_eq!(stubs.sol_get_rent_sysvar(var_addr), SUCCESS);
+            assert_eq!(
+                unsafe { std::ptr::read_unaligned(var_addr as *const Rent) },
+                rent
+            );
+        });
+    }
+
+    /// Returns every `InstructionError` variant.
+    fn all_instruction_errors() -> Vec<InstructionError> {
+        let errors = vec![
+            InstructionError::GenericError,
+            InstructionError::InvalidArgument,
+            InstructionError::InvalidInstructionData,
+            InstructionError::InvalidAccountData,
+            InstructionError::AccountDataTooSmall,
+            InstructionError::InsufficientFunds,
+            InstructionError::IncorrectProgramId,
+            InstructionError::MissingRequiredSignature,
+            InstructionError::AccountAlreadyInitialized,
+            InstructionError::UninitializedAccount,
+            InstructionError::UnbalancedInstruction,
+            InstructionError::ModifiedProgramId,
+            InstructionError::ExternalAccountLamportSpend,
+            InstructionError::ExternalAccountDataModified,
+            InstructionError::ReadonlyLamportChange,
+            InstructionError::ReadonlyDataModified,
+            InstructionError::DuplicateAccountIndex,
+            InstructionError::ExecutableModified,
+            InstructionError::RentEpochModified,
+            InstructionError::NotEnoughAccountKeys,
+            InstructionError::AccountDataSizeChanged,
+            InstructionError::AccountNotExecutable,
+            InstructionError::AccountBorrowFailed,
+            InstructionError::AccountBorrowOutstanding,
+            InstructionError::DuplicateAccountOutOfSync,
+            InstructionError::Custom(42),
+            InstructionError::InvalidError,
+            InstructionError::ExecutableDataModified,
+            InstructionError::ExecutableLamportChange,
+            InstructionError::ExecutableAccountNotRentExempt,
+            InstructionError::UnsupportedProgramId,
+            InstructionError::CallDepth,
+            InstructionError::MissingAccount,
+            InstructionError::ReentrancyNotAllowed,
+            InstructionError::MaxSeedLengthExceeded,
+            InstructionError::InvalidSeeds,
+            InstructionError::InvalidRealloc,
+            InstructionError::ComputationalBudgetExceeded,
+            InstructionError::PrivilegeEscalation,
+            InstructionError::ProgramEnvironmentSetupFailure,
+            InstructionError::ProgramFailedToComplete,
+            InstructionError::ProgramFailedToCompile,
+            InstructionError::Immutable,
+            InstructionError::IncorrectAuthority,
+            InstructionError::BorshIoError("unexpected end of input".to_string()),
+            InstructionError::AccountNotRentExempt,
+            InstructionError::InvalidAccountOwner,
+            InstructionError::ArithmeticOverflow,
+            InstructionError::UnsupportedSysvar,
+            InstructionError::IllegalOwner,
+            InstructionError::MaxAccountsDataAllocationsExceeded,
+            InstructionError::MaxAccountsExceeded,
+            InstructionError::MaxInstructionTraceLengthExceeded,
+            InstructionError::BuiltinProgramsMustConsumeComputeUnits,
+        ];
+        // Fails to compile once a variant is added, as a reminder to list it above
+        for error in &errors {
+            match error {
+                InstructionError::GenericError
+                | InstructionError::InvalidArgument
+                | InstructionError::InvalidInstructionData
+                | InstructionError::InvalidAccountData
+                | InstructionError::AccountDataTooSmall
+                | InstructionError::InsufficientFunds
+                | InstructionError::IncorrectProgramId
+                | InstructionError::MissingRequiredSignature
+                | InstructionError::AccountAlreadyInitialized
+                | InstructionError::UninitializedAccount
+                | InstructionError::UnbalancedInstruction
+                | InstructionError::ModifiedProgramId
+                | InstructionError::ExternalAccountLamportSpend
+                | InstructionError::ExternalAccountDataModified
+                | InstructionError::ReadonlyLamportChange
+                | InstructionError::ReadonlyDataModified
+                | InstructionError::DuplicateAccountIndex
+                | InstructionError::ExecutableModified
+                | InstructionError::RentEpochModified
+                | InstructionError::NotEnoughAccountKeys
+                | InstructionError::AccountDataSizeChanged
+                | InstructionError::AccountNotExecutable
+                | InstructionError::AccountBorrowFailed
+                | InstructionError::AccountBorrowOutstanding
+                | InstructionError::DuplicateAccountOutOfSync
+                | InstructionError::Custom(_)
+                | InstructionError::InvalidError
+                | InstructionError::ExecutableDataModified
+                | InstructionError::ExecutableLamportChange
+                | InstructionError::ExecutableAccountNotRentExempt
+                | InstructionError::UnsupportedProgramId
+                | InstructionError::CallDepth
+                | InstructionError::MissingAccount
+                | InstructionError::ReentrancyNotAllowed
+                | InstructionError::MaxSeedLengthExceeded
+                | InstructionError::InvalidSeeds
+                | InstructionError::InvalidRealloc
+                | InstructionError::ComputationalBudgetExceeded
+                | InstructionError::PrivilegeEscalation
+                | InstructionError::ProgramEnvironmentSetupFailure
+                | InstructionError::ProgramFailedToComplete
+                | InstructionError::ProgramFailedToCompile
+                | InstructionError::Immutable
+                | InstructionError::IncorrectAuthority
+                | InstructionError::BorshIoError(_)
+                | InstructionError::AccountNotRentExempt
+                | InstructionError::InvalidAccountOwner
+                | InstructionError::ArithmeticOverflow
+                | InstructionError::UnsupportedSysvar
+                | InstructionError::IllegalOwner
+                | InstructionError::MaxAccountsDataAllocationsExceeded
+                | InstructionError::MaxAccountsExceeded
+                | InstructionError::MaxInstructionTraceLengthExceeded
+                | InstructionError::BuiltinProgramsMustConsumeComputeUnits => {}
+            }
         }
-        InstructionError::MaxAccountsExceeded => {
-            Err(solana_sdk::instruction::InstructionError::MaxAccountsExceeded)
+        errors
+    }
+
+    #[test]
+    fn instruction_errors_without_a_program_error_become_invalid_argument() {
+        let mut representable = 0;
+        for error in all_instruction_errors() {
+            let program_error = instruction_error_to_program_error(error.clone());
+            if program_error_to_instruction_error(&program_error) == error {
+                representable += 1;
+            } else {
+                assert_eq!(program_error, ProgramError::InvalidArgument, "{error:?}");
+            }
         }
-        InstructionError::MaxInstructionTraceLengthExceeded => {
-            Ok(ProgramError::MaxInstructionTraceLengthExceeded)
+        // Every ProgramError except InvalidArgument, which is representable as well
+        assert_eq!(representable, 26);
+    }
+
+    #[test]
+    fn sol_invoke_signed_keeps_the_callee_error() {
+        for error in all_instruction_errors() {
+            // A fresh context per error, as the instruction trace is limited
+            with_mock_caller(Vec::new(), &[(CALLEE_ID, Fail::vm)], || {
+                let instruction = Instruction::new_with_bytes(
+                    CALLEE_ID,
+                    &serde_json::to_vec(&error).unwrap(),
+                    Vec::new(),
+                );
+                assert_eq!(
+                    TridentSyscallStubs::default().sol_invoke_signed(&instruction, &[], &[]),
+                    Err(instruction_error_to_program_error(error.clone()))
+                );
+                assert_eq!(
+                    take_last_cpi_error(),
+                    Some(TridentStubError::CalleeFailed(error.clone()))
+                );
+                assert_eq!(take_last_cpi_error(), None);
+            });
         }
-        InstructionError::BuiltinProgramsMustConsumeComputeUnits => {
-            Ok(ProgramError::BuiltinProgramsMustConsumeComputeUnits)
+    }
+
+    #[test]
+    fn cpi_trace_records_the_unconverted_error() {
+        with_mock_caller(Vec::new(), &[(CALLEE_ID, Fail::vm)], || {
+            let error = InstructionError::ExecutableModified;
+            let instruction = Instruction::new_with_bytes(
+                CALLEE_ID,
+                &serde_json::to_vec(&error).unwrap(),
+                Vec::new(),
+            );
+            assert_eq!(
+                TridentSyscallStubs::default().sol_invoke_signed(&instruction, &[], &[]),
+                Err(ProgramError::InvalidArgument)
+            );
+            let cpi_trace = take_cpi_trace();
+            assert_eq!(cpi_trace.len(), 1);
+            assert_eq!(
+                cpi_trace[0].result,
+                Err(TridentStubError::CalleeFailed(error))
+            );
+        });
+    }
+
+    #[test]
+    fn programs_executing_on_different_threads_do_not_interfere() {
+        let barrier = std::sync::Barrier::new(2);
+        std::thread::scope(|scope| {
+            for thread_index in 0..2u8 {
+                let barrier = &barrier;
+                scope.spawn(move || {
+                    let callee_id = Pubkey::new_from_array([thread_index + 10; 32]);
+                    let clock = Clock {
+                        slot: thread_index as u64,
+                        ..Clock::default()
+                    };
+                    barrier.wait();
+                    for iteration in 0..200u8 {
+                        let accounts =
+                            vec![(Clock::id(), create_account_shared_data_for_test(&clock))];
+                        with_mock_caller(accounts, &[(callee_id, SetReturnData::vm)], || {
+                            let stubs = TridentSyscallStubs::default();
+                            let data = [thread_index, iteration];
+                            let instruction =
+                                Instruction::new_with_bytes(callee_id, &data, Vec::new());
+                            assert_eq!(stubs.sol_invoke_signed(&instruction, &[], &[]), Ok(()));
+                            assert_eq!(
+                                take_program_logs(),
+                                [
+                                    format!("Program {} invoke [2]", callee_id),
+                                    format!("Program {} success", callee_id),
+                                ]
+                            );
+                            assert_eq!(
+                                stubs.sol_get_return_data(),
+                                Some((CALLEE_ID, data.to_vec()))
+                            );
+
+                            let mut served = Clock::default();
+                            assert_eq!(
+                                stubs.sol_get_clock_sysvar(&mut served as *mut Clock as *mut u8),
+                                SUCCESS
+                            );
+                            assert_eq!(served, clock);
+                        });
+                    }
+                });
+            }
+        });
+    }
+
+    #[test]
+    fn sol_invoke_signed_copies_back_the_owner_assigned_by_the_callee() {
+        let pubkey = Pubkey::new_unique();
+        let account = AccountSharedData::new(1_000_000, 0, &system_program::id());
+        with_mock_caller(
+            vec![(pubkey, account)],
+            &[(system_program::id(), CreateAccount::vm)],
+            || {
+                let mut input = serialize_instruction_accounts();
+                let account_infos = account_infos(&mut input);
+                let instruction = Instruction::new_with_bytes(
+                    system_program::id(),
+                    CALLER_PROGRAM_ID.as_ref(),
+                    vec![AccountMeta::new(pubkey, true)],
+                );
+
+                assert_eq!(
+                    TridentSyscallStubs::default().sol_invoke_signed(
+                        &instruction,
+                        &account_infos,
+                        &[]
+                    ),
+                    Ok(())
+                );
+                assert_eq!(*account_infos[0].owner, CALLER_PROGRAM_ID);
+                assert_eq!(*account_infos[0].data.borrow(), [0; 8]);
+                assert_eq!(*transaction_account(&pubkey).owner(), CALLER_PROGRAM_ID);
+            },
+        );
+    }
+
+    #[test]
+    fn sol_invoke_signed_rejects_deploying_a_program() {
+        let pubkey = Pubkey::new_unique();
+        let account = AccountSharedData::new(1_000_000_000, 0, &bpf_loader_upgradeable::id());
+        with_mock_caller(
+            vec![(pubkey, account)],
+            &[(bpf_loader_upgradeable::id(), SetReturnData::vm)],
+            || {
+                let mut input = serialize_instruction_accounts();
+                let account_infos = account_infos(&mut input);
+                let stubs = TridentSyscallStubs::default();
+                let invoke_loader = |loader_instruction| {
+                    let instruction = Instruction::new_with_bincode(
+                        bpf_loader_upgradeable::id(),
+                        &loader_instruction,
+                        vec![AccountMeta::new(pubkey, false)],
+                    );
+                    stubs.sol_invoke_signed(&instruction, &account_infos, &[])
+                };
+
+                assert_eq!(
+                    invoke_loader(UpgradeableLoaderInstruction::DeployWithMaxDataLen {
+                        max_data_len: 0
+                    }),
+                    Err(ProgramError::InvalidArgument)
+                );
+                assert_eq!(
+                    take_last_cpi_error(),
+                    Some(TridentStubError::InvalidInvocation(
+                        InstructionError::ProgramFailedToComplete
+                    ))
+                );
+                assert_eq!(stubs.sol_get_return_data(), None);
+
+                // Upgrades are among the loader instructions programs may invoke
+                assert_eq!(invoke_loader(UpgradeableLoaderInstruction::Upgrade), Ok(()));
+                assert_eq!(
+                    stubs.sol_get_return_data(),
+                    Some((CALLEE_ID, vec![3, 0, 0, 0]))
+                );
+            },
+        );
+    }
+
+    #[test]
+    fn sol_invoke_signed_rejects_invoking_the_other_loaders() {
+        with_mock_caller(Vec::new(), &[], || {
+            for program_id in [
+                native_loader::id(),
+                bpf_loader::id(),
+                bpf_loader_deprecated::id(),
+            ] {
+                let instruction = Instruction::new_with_bytes(program_id, &[], Vec::new());
+                assert_eq!(
+                    TridentSyscallStubs::default().sol_invoke_signed(&instruction, &[], &[]),
+                    Err(ProgramError::InvalidArgument)
+                );
+                assert_eq!(
+                    take_last_cpi_error(),
+                    Some(TridentStubError::InvalidInvocation(
+                        InstructionError::ProgramFailedToComplete
+                    ))
+                );
+            }
+        });
+    }
+
+    #[test]
+    fn sol_invoke_signed_ignores_the_callers_executable_flag() {
+        let (pubkey, account) = callee_account(&[]);
+        with_mock_caller(
+            vec![(pubkey, account)],
+            &[(CALLEE_ID, WriteData::vm)],
+            || {
+                let mut input = serialize_instruction_accounts();
+                let mut account_infos = account_infos(&mut input);
+                account_infos[0].executable = true;
+                let instruction = Instruction::new_with_bytes(
+                    CALLEE_ID,
+                    &[],
+                    vec![AccountMeta::new(pubkey, false)],
+                );
+
+                assert_eq!(
+                    TridentSyscallStubs::default().sol_invoke_signed(
+                        &instruction,
+                        &account_infos,
+                        &[]
+                    ),
+                    Ok(())
+                );
+                assert!(!transaction_account(&pubkey).executable());
+            },
+        );
+    }
+
+    #[test]
+    fn sol_invoke_signed_invokes_upgradeable_programs_through_their_loader() {
+        let program_id = Pubkey::new_unique();
+        // The ProgramData account is not loaded, as the program cache serves the program
+        let mut program_account = AccountSharedData::new_data(
+            1,
+            &UpgradeableLoaderState::Program {
+                programdata_address: Pubkey::new_unique(),
+            },
+            &bpf_loader_upgradeable::id(),
+        )
+        .unwrap();
+        program_account.set_executable(true);
+
+        with_mock_caller(
+            vec![(program_id, program_account)],
+            &[(bpf_loader_upgradeable::id(), SetReturnData::vm)],
+            || {
+                let stubs = TridentSyscallStubs::default();
+                let instruction = Instruction::new_with_bytes(program_id, &[1, 2, 3], Vec::new());
+                assert_eq!(stubs.sol_invoke_signed(&instruction, &[], &[]), Ok(()));
+                assert_eq!(
+                    stubs.sol_get_return_data(),
+                    Some((CALLEE_ID, vec![1, 2, 3]))
+                );
+            },
+        );
+    }
+
+    #[test]
+    fn sol_invoke_signed_ignores_the_callers_rent_epoch() {
+        let (pubkey, account) = callee_account(&[]);
+        with_mock_caller(
+            vec![(pubkey, account)],
+            &[(CALLEE_ID, WriteData::vm)],
+            || {
+                let mut input = serialize_instruction_accounts();
+                let mut account_infos = account_infos(&mut input);
+                account_infos[0].rent_epoch = 5;
+                let instruction = Instruction::new_with_bytes(
+                    CALLEE_ID,
+                    &[],
+                    vec![AccountMeta::new(pubkey, false)],
+                );
+
+                assert_eq!(
+                    TridentSyscallStubs::default().sol_invoke_signed(
+                        &instruction,
+                        &account_infos,
+                        &[]
+                    ),
+                    Ok(())
+                );
+                assert_eq!(transaction_account(&pubkey).rent_epoch(), 0);
+            },
+        );
+    }
+
+    #[test]
+    fn sol_invoke_signed_rejects_growth_beyond_the_callers_spare_capacity() {
+        let (pubkey, account) = callee_account(&[0]);
+        let (grown_pubkey, grown_account) = callee_account(&[]);
+        with_mock_caller(
+            vec![(pubkey, account), (grown_pubkey, grown_account)],
+            &[(CALLEE_ID, ResizeData::vm)],
+            || {
+                let mut input = serialize_instruction_accounts();
+                let account_infos = account_infos(&mut input);
+                let stubs = TridentSyscallStubs::default();
+
+                // Uses up the padding behind the second account's data
+                let instruction = Instruction::new_with_bytes(
+                    CALLEE_ID,
+                    &(MAX_PERMITTED_DATA_INCREASE as u64).to_le_bytes(),
+                    vec![AccountMeta::new(grown_pubkey, false)],
+                );
+                assert_eq!(
+                    stubs.sol_invoke_signed(&instruction, &account_infos, &[]),
+                    Ok(())
+                );
+                take_program_logs();
+
+                // Grows the second account by one more byte, which the CPI itself allows
+                let new_len = MAX_PERMITTED_DATA_INCREASE + 1;
+                let instruction = Instruction::new_with_bytes(
+                    CALLEE_ID,
+                    &(new_len as u64).to_le_bytes(),
+                    vec![
+                        AccountMeta::new(pubkey, false),
+                        AccountMeta::new(grown_pubkey, false),
+                    ],
+                );
+                assert_eq!(
+                    stubs.sol_invoke_signed(&instruction, &account_infos, &[]),
+                    Err(ProgramError::InvalidRealloc)
+                );
+                assert!(take_program_logs().contains(&format!(
+                    "Failed to resize account {} to {} bytes",
+                    grown_pubkey, new_len
+                )));
+                // The first account is not written back either
+                assert_eq!(*account_infos[0].data.borrow(), [0]);
+                assert_eq!(account_infos[1].data_len(), MAX_PERMITTED_DATA_INCREASE);
+            },
+        );
+    }
+
+    fn check_size(data_len: usize, num_accounts: usize, num_account_infos: usize) -> bool {
+        let key = Pubkey::new_unique();
+        let mut lamports = 0;
+        let mut data = [];
+        let account_info =
+            AccountInfo::new(&key, false, false, &mut lamports, &mut data, &key, false, 0);
+        let instruction = Instruction::new_with_bytes(
+            CALLEE_ID,
+            &vec![0; data_len],
+            vec![AccountMeta::new(key, false); num_accounts],
+        );
+        let result = check_instruction_size(
+            get_invoke_context(),
+            &instruction,
+            &vec![account_info; num_account_infos],
+        );
+        match result {
+            Ok(()) => true,
+            Err(err) => {
+                assert_eq!(
+                    err,
+                    TridentStubError::InvalidInvocation(InstructionError::ProgramFailedToComplete)
+                );
+                false
+            }
         }
     }
+
+    #[test]
+    fn cpi_size_limits_allow_the_exact_limits() {
+        with_mock_caller(Vec::new(), &[], || {
+            assert!(check_size(MAX_CPI_INSTRUCTION_DATA_LEN, 0, 0));
+            assert!(!check_size(MAX_CPI_INSTRUCTION_DATA_LEN + 1, 0, 0));
+            assert!(check_size(0, MAX_CPI_INSTRUCTION_ACCOUNTS, 0));
+            assert!(!check_size(0, MAX_CPI_INSTRUCTION_ACCOUNTS + 1, 0));
+            assert!(check_size(0, 0, MAX_CPI_ACCOUNT_INFOS));
+            assert!(!check_size(0, 0, MAX_CPI_ACCOUNT_INFOS + 1));
+
+            deactivate_feature(&increase_tx_account_lock_limit::id());
+            assert!(check_size(0, 0, 64));
+            assert!(!check_size(0, 0, 65));
+        });
+    }
+
+    #[test]
+    fn cpi_size_limits_without_loosen_cpi_size_restriction() {
+        with_mock_caller(Vec::new(), &[], || {
+            deactivate_feature(&loosen_cpi_size_restriction::id());
+            let max_size = get_invoke_context()
+                .get_compute_budget()
+                .max_cpi_instruction_size;
+            let meta_size = size_of::<AccountMeta>();
+
+            assert!(check_size(max_size, 0, 0));
+            assert!(!check_size(max_size + 1, 0, 0));
+            assert!(check_size(max_size - meta_size, 1, 0));
+            assert!(!check_size(max_size - meta_size + 1, 1, 0));
+            let max_account_infos = max_size / size_of::<Pubkey>();
+            assert!(check_size(0, 0, max_account_infos));
+            assert!(!check_size(0, 0, max_account_infos + 1));
+        });
+    }
 }
@@ -0,0 +1,127 @@
+use std::cell::RefCell;
+
+use solana_sdk::pubkey::Pubkey;
+
+/// A syscall made by the program, with its arguments summarized.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SyscallEvent {
+    Log {
+        message: String,
+    },
+    LogData {
+        field_lengths: Vec<usize>,
+    },
+    LogComputeUnits,
+    RemainingComputeUnits,
+    /// A read through one of the typed sysvar getters, such as `Clock::get`.
+    GetSysvar {
+        sysvar_id: Pubkey,
+    },
+    /// A read through `sol_get_sysvar`.
+    GetSysvarSlice {
+        sysvar_id: Pubkey,
+        offset: u64,
+        length: u64,
+    },
+    /// `None` requests the total epoch stake.
+    GetEpochStake {
+        vote_address: Option<Pubkey>,
+    },
+    Invoke {
+        program_id: Pubkey,
+        accounts: usize,
+        data_len: usize,
+        signers: usize,
+    },
+    Memcpy {
+        n: usize,
+    },
+    Memmove {
+        n: usize,
+    },
+    Memcmp {
+        n: usize,
+    },
+    Memset {
+        n: usize,
+    },
+    GetReturnData,
+    SetReturnData {
+        data_len: usize,
+    },
+    GetStackHeight,
+}
+
+thread_local! {
+    static SYSCALL_TRACE: RefCell<Option<Vec<SyscallEvent>>> = const { RefCell::new(None) };
+}
+
+/// Starts recording the syscalls made on the current thread.
+pub fn enable_syscall_trace() {
+    SYSCALL_TRACE.with(|trace| {
+        trace.borrow_mut().get_or_insert_with(Vec::new);
+    });
+}
+
+/// Stops recording syscalls and discards the events not taken yet.
+pub fn disable_syscall_trace() {
+    SYSCALL_TRACE.with(|trace| trace.replace(None));
+}
+
+/// Returns the syscalls recorded on the current thread since the last call, oldest first.
+/// Recording stays enabled.
+pub fn take_syscall_trace() -> Vec<SyscallEvent> {
+    SYSCALL_TRACE
+        .with(|trace| trace.borrow_mut().as_mut().map(std::mem::take))
+        .unwrap_or_default()
+}
+
+/// Appends the event built by `event` if recording is enabled; the event is not built
+/// otherwise.
+pub(crate) fn record(event: impl FnOnce() -> SyscallEvent) {
+    SYSCALL_TRACE.with(|trace| {
+        if let Some(trace) = trace.borrow_mut().as_mut() {
+            trace.push(event());
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::with_mock_caller;
+    use crate::TridentSyscallStubs;
+
+    use solana_sdk::program_stubs::SyscallStubs;
+
+    #[test]
+    fn records_syscalls_in_order_while_enabled() {
+        let stubs = TridentSyscallStubs::default();
+
+        with_mock_caller(Vec::new(), &[], || {
+            enable_syscall_trace();
+            stubs.sol_log("message");
+            stubs.sol_log_data(&[b"ab", b"c"]);
+            stubs.sol_remaining_compute_units();
+            assert_eq!(
+                take_syscall_trace(),
+                [
+                    SyscallEvent::Log {
+                        message: "message".to_string(),
+                    },
+                    SyscallEvent::LogData {
+                        field_lengths: vec![2, 1],
+                    },
+                    SyscallEvent::RemainingComputeUnits,
+                ]
+            );
+            assert_eq!(take_syscall_trace(), []);
+
+            disable_syscall_trace();
+            stubs.sol_log("message");
+            enable_syscall_trace();
+            assert_eq!(take_syscall_trace(), []);
+            disable_syscall_trace();
+        });
+    }
+}
@@ -0,0 +1,135 @@
+use crate::invoke_context::with_context_state;
+use crate::sysvars::current_sysvar;
+use crate::sysvars::override_sysvar_data;
+use crate::sysvars::SERIALIZED_SYSVAR_IDS;
+use crate::try_get_invoke_context;
+
+use std::collections::BTreeMap;
+
+use base64::prelude::BASE64_STANDARD;
+use base64::Engine;
+use serde::de::Error;
+use serde::Deserialize;
+use serde::Deserializer;
+use serde::Serialize;
+use serde::Serializer;
+use solana_sdk::account::create_account_shared_data_with_fields;
+use solana_sdk::account::ReadableAccount;
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::sysvar;
+
+use solana_program_runtime::sysvar_cache::SysvarCache;
+
+/// The serialized sysvars the stubs serve, keyed by sysvar id, so the sysvars in effect
+/// when a crash happened can be stored with it and installed again to reproduce it.
+///
+/// Serializes as a map from base58 sysvar id to base64 data, which formats without
+/// non-string map keys such as JSON support.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SysvarSnapshot {
+    #[serde(serialize_with = "serialize_sysvars")]
+    #[serde(deserialize_with = "deserialize_sysvars")]
+    pub sysvars: BTreeMap<Pubkey, Vec<u8>>,
+}
+
+impl SysvarSnapshot {
+    /// Captures every sysvar the stubs currently serve, including overrides.
+    pub fn capture() -> Self {
+        let mut sysvars = BTreeMap::new();
+        for id in SERIALIZED_SYSVAR_IDS {
+            let data =
+                with_context_state(|state| state.sysvar_data.get(&id).cloned()).or_else(|| {
+                    try_get_invoke_context()?
+                        .get_sysvar_cache()
+                        .sysvar_id_to_buffer(&id)
+                        .clone()
+                });
+            if let Some(data) = data {
+                sysvars.insert(id, data);
+            }
+        }
+
+        // The sysvar cache keeps no serialized copy of the deprecated Fees sysvar
+        #[allow(deprecated)]
+        if let Ok(fees) = current_sysvar(SysvarCache::get_fees) {
+            let data = create_account_shared_data_with_fields(&fees, (0, 0))
+                .data()
+                .to_vec();
+            sysvars.insert(sysvar::fees::id(), data);
+        }
+
+        Self { sysvars }
+    }
+
+    /// Installs the captured sysvars as overrides, which the stubs serve before the sysvar
+    /// cache for the rest of the current invoke context.
+    pub fn restore(&self) {
+        for (id, data) in &self.sysvars {
+            override_sysvar_data(*id, data.clone());
+        }
+    }
+}
+
+fn serialize_sysvars<S: Serializer>(
+    sysvars: &BTreeMap<Pubkey, Vec<u8>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_map(
+        sysvars
+            .iter()
+            .map(|(id, data)| (id.to_string(), BASE64_STANDARD.encode(data))),
+    )
+}
+
+fn deserialize_sysvars<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<BTreeMap<Pubkey, Vec<u8>>, D::Error> {
+    BTreeMap::<String, String>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(id, data)| {
+            let id = id.parse().map_err(D::Error::custom)?;
+            let data = BASE64_STANDARD.decode(data).map_err(D::Error::custom)?;
+            Ok((id, data))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::with_mock_caller;
+
+    use solana_sdk::account::create_account_shared_data_for_test;
+    use solana_sdk::clock::Clock;
+    use solana_sdk::rent::Rent;
+    use solana_sdk::sysvar::SysvarId;
+
+    #[test]
+    fn snapshots_round_trip_through_json() {
+        let clock = Clock {
+            slot: 7,
+            unix_timestamp: 42,
+            ..Clock::default()
+        };
+        let accounts = vec![
+            (Clock::id(), create_account_shared_data_for_test(&clock)),
+            (
+                Rent::id(),
+                create_account_shared_data_for_test(&Rent::default()),
+            ),
+        ];
+
+        with_mock_caller(accounts, &[], || {
+            let snapshot = SysvarSnapshot::capture();
+            assert!(snapshot.sysvars.contains_key(&Clock::id()));
+            assert!(snapshot.sysvars.contains_key(&Rent::id()));
+
+            let json = serde_json::to_string(&snapshot).unwrap();
+            assert!(json.contains(&Clock::id().to_string()));
+            assert_eq!(
+                serde_json::from_str::<SysvarSnapshot>(&json).unwrap(),
+                snapshot
+            );
+        });
+    }
+}
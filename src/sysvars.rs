@@ -0,0 +1,468 @@
+use crate::error::TridentStubError;
+use crate::get_invoke_context;
+use crate::invoke_context::with_context_state;
+use crate::try_get_invoke_context;
+
+use std::cell::Cell;
+use std::collections::hash_map::Entry;
+use std::sync::Arc;
+
+use solana_sdk::account::create_account_shared_data_with_fields;
+use solana_sdk::account::from_account;
+use solana_sdk::account::Account;
+use solana_sdk::account::ReadableAccount;
+use solana_sdk::clock::Clock;
+use solana_sdk::clock::Epoch;
+use solana_sdk::clock::Slot;
+use solana_sdk::clock::UnixTimestamp;
+use solana_sdk::epoch_rewards::EpochRewards;
+use solana_sdk::fee_calculator::FeeCalculator;
+use solana_sdk::instruction::InstructionError;
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::rent::Rent;
+use solana_sdk::sysvar;
+#[allow(deprecated)]
+use solana_sdk::sysvar::fees::Fees;
+use solana_sdk::sysvar::last_restart_slot::LastRestartSlot;
+use solana_sdk::sysvar::Sysvar;
+
+use solana_program_runtime::sysvar_cache::SysvarCache;
+
+/// Sysvars the sysvar cache keeps serialized, and which are therefore served in full.
+pub(crate) const SERIALIZED_SYSVAR_IDS: [Pubkey; 7] = [
+    sysvar::clock::ID,
+    sysvar::epoch_rewards::ID,
+    sysvar::epoch_schedule::ID,
+    sysvar::last_restart_slot::ID,
+    sysvar::rent::ID,
+    sysvar::slot_hashes::ID,
+    sysvar::stake_history::ID,
+];
+
+thread_local! {
+    static AUTO_REFRESH_SYSVAR_CACHE: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Returns the sysvar served to the program, deserializing it from the overridden data or
+/// the sysvar cache only on the first read within the current invoke context.
+pub(crate) fn current_sysvar<T: Sysvar + Clone + 'static>(
+    load: fn(&SysvarCache) -> Result<Arc<T>, InstructionError>,
+) -> Result<T, TridentStubError> {
+    with_context_state(|state| {
+        let sysvar = match state.sysvars.entry(T::id()) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) if state.sysvar_data.contains_key(&T::id()) => {
+                let account = Account {
+                    data: state.sysvar_data[&T::id()].clone(),
+                    ..Account::default()
+                };
+                let sysvar = from_account::<T, _>(&account)
+                    .ok_or(TridentStubError::SysvarUnavailable { id: T::id() })?;
+                entry.insert(Box::new(sysvar))
+            }
+            Entry::Vacant(entry) => {
+                let invoke_context =
+                    try_get_invoke_context().ok_or(TridentStubError::MissingInvokeContext)?;
+                let sysvar = load(invoke_context.get_sysvar_cache())
+                    .map_err(|_| TridentStubError::SysvarUnavailable { id: T::id() })?;
+                entry.insert(Box::new(T::clone(&sysvar)))
+            }
+        };
+        let sysvar = sysvar
+            .downcast_ref::<T>()
+            .expect("sysvars are cached under their own id");
+        Ok(sysvar.clone())
+    })
+}
+
+/// Makes the stubs serve `sysvar` instead of the sysvar cache entry for the rest of the
+/// current invoke context, both through the typed getters and `sol_get_sysvar`.
+pub(crate) fn override_sysvar<T: Sysvar + Clone + 'static>(sysvar: T) {
+    let data = create_account_shared_data_with_fields(&sysvar, (0, 0))
+        .data()
+        .to_vec();
+    with_context_state(|state| {
+        state.pinned_sysvars.insert(T::id());
+        state.sysvar_data.insert(T::id(), data);
+        state.sysvars.insert(T::id(), Box::new(sysvar));
+    });
+}
+
+/// Makes the stubs serve the serialized sysvar `data` under `id` instead of the sysvar
+/// cache entry for the rest of the current invoke context.
+pub(crate) fn override_sysvar_data(id: Pubkey, data: Vec<u8>) {
+    with_context_state(|state| {
+        state.pinned_sysvars.insert(id);
+        state.sysvars.remove(&id);
+        state.sysvar_data.insert(id, data);
+    });
+}
+
+/// Sets the Clock the stubs serve for the current invoke context.
+pub fn set_clock(clock: Clock) {
+    override_sysvar(clock);
+}
+
+/// Moves the served Clock to `slot`, keeping its other fields.
+pub fn warp_to_slot(slot: Slot) {
+    let mut clock = current_sysvar(SysvarCache::get_clock).unwrap_or_default();
+    clock.slot = slot;
+    set_clock(clock);
+}
+
+/// Moves the served Clock to the unix timestamp `timestamp`, keeping its other fields.
+pub fn warp_to_timestamp(timestamp: UnixTimestamp) {
+    let mut clock = current_sysvar(SysvarCache::get_clock).unwrap_or_default();
+    clock.unix_timestamp = timestamp;
+    set_clock(clock);
+}
+
+/// Sets the Rent the stubs serve for the current invoke context.
+pub fn set_rent(rent: Rent) {
+    override_sysvar(rent);
+}
+
+/// Sets the lamports per signature of the deprecated Fees sysvar the stubs serve for the
+/// current invoke context. The sysvar stays unavailable while `disable_fees_sysvar` is
+/// active.
+#[allow(deprecated)]
+pub fn set_fees(lamports_per_signature: u64) {
+    override_sysvar(Fees::new(&FeeCalculator::new(lamports_per_signature)));
+}
+
+/// Sets the EpochRewards the stubs serve for the current invoke context.
+pub fn set_epoch_rewards(epoch_rewards: EpochRewards) {
+    override_sysvar(epoch_rewards);
+}
+
+/// Sets the slot of the last cluster restart the stubs serve for the current invoke
+/// context.
+pub fn set_last_restart_slot(slot: Slot) {
+    override_sysvar(LastRestartSlot {
+        last_restart_slot: slot,
+    });
+}
+
+/// Sets how many milliseconds a slot lasts when `warp_to_epoch` and `advance_epochs` move
+/// the Clock's timestamps. Without it the timestamps are left unchanged.
+pub fn set_slot_duration_ms(ms_per_slot: u64) {
+    with_context_state(|state| state.ms_per_slot = Some(ms_per_slot));
+}
+
+/// Moves the served Clock to the first slot of `epoch`, following the served EpochSchedule,
+/// including its warmup epochs.
+pub fn warp_to_epoch(epoch: Epoch) {
+    let epoch_schedule = current_sysvar(SysvarCache::get_epoch_schedule).unwrap_or_default();
+    let mut clock = current_sysvar(SysvarCache::get_clock).unwrap_or_default();
+
+    let slot = epoch_schedule.get_first_slot_in_epoch(epoch);
+    if let Some(ms_per_slot) = with_context_state(|state| state.ms_per_slot) {
+        let elapsed_ms = (slot as i64)
+            .saturating_sub(clock.slot as i64)
+            .saturating_mul(ms_per_slot as i64);
+        clock.unix_timestamp = clock.unix_timestamp.saturating_add(elapsed_ms / 1000);
+        clock.epoch_start_timestamp = clock.unix_timestamp;
+    }
+    clock.slot = slot;
+    clock.epoch = epoch;
+    clock.leader_schedule_epoch = epoch_schedule.get_leader_schedule_epoch(slot);
+    set_clock(clock);
+}
+
+/// Moves the served Clock forward by `epochs` epochs, see `warp_to_epoch`.
+pub fn advance_epochs(epochs: u64) {
+    let clock = current_sysvar(SysvarCache::get_clock).unwrap_or_default();
+    warp_to_epoch(clock.epoch.saturating_add(epochs));
+}
+
+/// Copies the sysvars the stubs serve, including overrides, into the matching sysvar
+/// accounts of the current transaction, so programs reading a sysvar account see the same
+/// values as programs calling the sysvar getters. Call this before an instruction runs.
+///
+/// Only the account data is replaced; writability and other metadata are left alone.
+pub fn refresh_sysvar_accounts() -> Result<(), InstructionError> {
+    let invoke_context = get_invoke_context();
+    let transaction_context = &invoke_context.transaction_context;
+
+    for index_in_transaction in 0..transaction_context.get_number_of_accounts() {
+        let key = transaction_context.get_key_of_account_at_index(index_in_transaction)?;
+        let data = with_context_state(|state| state.sysvar_data.get(key).cloned()).or_else(|| {
+            invoke_context
+                .get_sysvar_cache()
+                .sysvar_id_to_buffer(key)
+                .clone()
+        });
+        if let Some(data) = data {
+            transaction_context
+                .get_account_at_index(index_in_transaction)?
+                .try_borrow_mut()
+                .map_err(|_| InstructionError::AccountBorrowFailed)?
+                .set_data_from_slice(&data);
+        }
+    }
+
+    Ok(())
+}
+
+/// Serves the data of the sysvar accounts in the current transaction in place of the
+/// sysvar cache, so sysvar accounts written directly are seen by the sysvar getters.
+///
+/// Sysvars set explicitly, such as through `set_clock` or `SysvarSnapshot::restore`, take
+/// precedence and are left alone.
+pub fn refresh_sysvar_cache_from_accounts() -> Result<(), InstructionError> {
+    let invoke_context = get_invoke_context();
+    let transaction_context = &invoke_context.transaction_context;
+
+    for id in SERIALIZED_SYSVAR_IDS {
+        if with_context_state(|state| state.pinned_sysvars.contains(&id)) {
+            continue;
+        }
+        let Some(index_in_transaction) = transaction_context.find_index_of_account(&id) else {
+            continue;
+        };
+        let data = transaction_context
+            .get_account_at_index(index_in_transaction)?
+            .try_borrow()
+            .map_err(|_| InstructionError::AccountBorrowFailed)?
+            .data()
+            .to_vec();
+        with_context_state(|state| {
+            state.sysvars.remove(&id);
+            state.sysvar_data.insert(id, data);
+        });
+    }
+
+    Ok(())
+}
+
+/// Makes every installed invoke context refresh the served sysvars from the sysvar
+/// accounts, see `refresh_sysvar_cache_from_accounts`.
+pub fn set_auto_refresh_sysvar_cache(enabled: bool) {
+    AUTO_REFRESH_SYSVAR_CACHE.with(|auto_refresh| auto_refresh.set(enabled));
+}
+
+pub(crate) fn auto_refresh_sysvar_cache() -> bool {
+    AUTO_REFRESH_SYSVAR_CACHE.with(Cell::get)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::activate_feature;
+    use crate::deactivate_feature;
+    use crate::set_invoke_context;
+    use crate::set_stubs_v2;
+    use crate::test_utils::account_infos;
+    use crate::test_utils::serialize_instruction_accounts;
+    use crate::test_utils::with_mock_caller;
+
+    use solana_sdk::account::create_account_shared_data_for_test;
+    use solana_sdk::epoch_schedule::EpochSchedule;
+    use solana_sdk::feature_set::disable_fees_sysvar;
+    use solana_sdk::program_error::ProgramError;
+
+    #[test]
+    fn served_sysvars_do_not_outlive_the_invoke_context() {
+        set_stubs_v2();
+        let clock = Clock {
+            slot: 7,
+            ..Clock::default()
+        };
+        let accounts = vec![(
+            sysvar::clock::id(),
+            create_account_shared_data_for_test(&clock),
+        )];
+
+        with_mock_caller(accounts, &[], || {
+            assert_eq!(Clock::get(), Ok(clock.clone()));
+
+            warp_to_slot(100);
+            assert_eq!(Clock::get().map(|clock| clock.slot), Ok(100));
+
+            // A new invoke context at the same address serves its own sysvars
+            set_invoke_context(get_invoke_context());
+            assert_eq!(Clock::get(), Ok(clock));
+        });
+    }
+
+    #[test]
+    fn set_rent_is_served_until_a_new_invoke_context() {
+        set_stubs_v2();
+        let accounts = vec![(
+            sysvar::rent::id(),
+            create_account_shared_data_for_test(&Rent::default()),
+        )];
+
+        with_mock_caller(accounts, &[], || {
+            let rent = Rent {
+                lamports_per_byte_year: 1,
+                ..Rent::default()
+            };
+            set_rent(rent.clone());
+            assert_eq!(Rent::get(), Ok(rent.clone()));
+            assert_eq!(Rent::get().unwrap().minimum_balance(0), 128 * 2);
+
+            set_invoke_context(get_invoke_context());
+            assert_eq!(Rent::get(), Ok(Rent::default()));
+        });
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn set_fees_is_served_while_the_fees_sysvar_is_enabled() {
+        set_stubs_v2();
+        // Charges a fee per signature unless fees are waived
+        let fee = |num_signatures: u64| {
+            let lamports_per_signature = Fees::get()?.fee_calculator.lamports_per_signature;
+            Ok::<_, ProgramError>(match lamports_per_signature {
+                0 => None,
+                lamports_per_signature => Some(lamports_per_signature * num_signatures),
+            })
+        };
+
+        with_mock_caller(Vec::new(), &[], || {
+            deactivate_feature(&disable_fees_sysvar::id());
+            set_fees(0);
+            assert_eq!(fee(2), Ok(None));
+            set_fees(5_000);
+            assert_eq!(fee(2), Ok(Some(10_000)));
+
+            activate_feature(&disable_fees_sysvar::id());
+            assert_eq!(fee(2), Err(ProgramError::UnsupportedSysvar));
+        });
+    }
+
+    #[test]
+    fn set_epoch_rewards_toggles_the_distribution() {
+        set_stubs_v2();
+        with_mock_caller(Vec::new(), &[], || {
+            let epoch_rewards = EpochRewards {
+                distribution_starting_block_height: 100,
+                total_rewards: 1_000,
+                active: true,
+                ..EpochRewards::default()
+            };
+            set_epoch_rewards(epoch_rewards.clone());
+            assert_eq!(EpochRewards::get(), Ok(epoch_rewards.clone()));
+
+            set_epoch_rewards(EpochRewards {
+                active: false,
+                ..epoch_rewards
+            });
+            assert!(!EpochRewards::get().unwrap().active);
+        });
+    }
+
+    #[test]
+    fn set_last_restart_slot_is_served_until_a_new_invoke_context() {
+        set_stubs_v2();
+        with_mock_caller(Vec::new(), &[], || {
+            set_last_restart_slot(42);
+            assert_eq!(
+                LastRestartSlot::get(),
+                Ok(LastRestartSlot {
+                    last_restart_slot: 42
+                })
+            );
+
+            set_invoke_context(get_invoke_context());
+            assert!(LastRestartSlot::get().is_err());
+        });
+    }
+
+    #[test]
+    fn warping_follows_the_epoch_schedule_through_its_warmup() {
+        set_stubs_v2();
+        let epoch_schedule = EpochSchedule::custom(512, 512, true);
+        let clock = Clock {
+            unix_timestamp: 1_000,
+            ..Clock::default()
+        };
+        let accounts = vec![
+            (
+                sysvar::clock::id(),
+                create_account_shared_data_for_test(&clock),
+            ),
+            (
+                sysvar::epoch_schedule::id(),
+                create_account_shared_data_for_test(&epoch_schedule),
+            ),
+        ];
+
+        with_mock_caller(accounts, &[], || {
+            // Epochs 0 to 3 are warmup epochs, 4 is the first normal one
+            for epoch in [1, 3, 4, 6] {
+                warp_to_epoch(epoch);
+                let clock = Clock::get().unwrap();
+                let slot = epoch_schedule.get_first_slot_in_epoch(epoch);
+                assert_eq!((clock.slot, clock.epoch), (slot, epoch));
+                assert_eq!(
+                    clock.leader_schedule_epoch,
+                    epoch_schedule.get_leader_schedule_epoch(slot)
+                );
+                // Timestamps stay put without a slot duration
+                assert_eq!(clock.unix_timestamp, 1_000);
+            }
+
+            warp_to_epoch(2);
+            set_slot_duration_ms(400);
+            advance_epochs(3);
+            let clock = Clock::get().unwrap();
+            let slot = epoch_schedule.get_first_slot_in_epoch(5);
+            assert_eq!((clock.slot, clock.epoch), (slot, 5));
+            let elapsed_ms = (slot - epoch_schedule.get_first_slot_in_epoch(2)) * 400;
+            assert_eq!(clock.unix_timestamp, 1_000 + elapsed_ms as i64 / 1_000);
+            assert_eq!(clock.epoch_start_timestamp, clock.unix_timestamp);
+        });
+    }
+
+    #[test]
+    fn refresh_sysvar_accounts_writes_overrides_into_the_accounts() {
+        let accounts = vec![(
+            sysvar::clock::id(),
+            create_account_shared_data_for_test(&Clock::default()),
+        )];
+
+        with_mock_caller(accounts, &[], || {
+            let clock = Clock {
+                slot: 7,
+                unix_timestamp: 42,
+                ..Clock::default()
+            };
+            set_clock(clock.clone());
+            refresh_sysvar_accounts().unwrap();
+
+            let mut input = serialize_instruction_accounts();
+            let account_infos = account_infos(&mut input);
+            assert_eq!(Clock::from_account_info(&account_infos[0]), Ok(clock));
+        });
+    }
+
+    #[test]
+    fn refresh_sysvar_cache_from_accounts_serves_edited_accounts() {
+        set_stubs_v2();
+        let accounts = vec![(
+            sysvar::clock::id(),
+            create_account_shared_data_for_test(&Clock::default()),
+        )];
+
+        with_mock_caller(accounts, &[], || {
+            assert_eq!(Clock::get(), Ok(Clock::default()));
+
+            let clock = Clock {
+                slot: 9,
+                ..Clock::default()
+            };
+            let transaction_context = &get_invoke_context().transaction_context;
+            transaction_context
+                .get_account_at_index(0)
+                .unwrap()
+                .borrow_mut()
+                .set_data_from_slice(create_account_shared_data_for_test(&clock).data());
+            assert_eq!(Clock::get(), Ok(Clock::default()));
+
+            refresh_sysvar_cache_from_accounts().unwrap();
+            assert_eq!(Clock::get(), Ok(clock));
+        });
+    }
+}
@@ -0,0 +1,141 @@
+use crate::get_invoke_context;
+use crate::with_invoke_context;
+
+use solana_sdk::account::AccountSharedData;
+use solana_sdk::account::WritableAccount;
+use solana_sdk::account_info::AccountInfo;
+use solana_sdk::entrypoint::deserialize;
+use solana_sdk::entrypoint::BPF_ALIGN_OF_U128;
+use solana_sdk::entrypoint::MAX_PERMITTED_DATA_INCREASE;
+use solana_sdk::entrypoint::NON_DUP_MARKER;
+use solana_sdk::native_loader;
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::transaction_context::IndexOfAccount;
+use solana_sdk::transaction_context::InstructionAccount;
+use solana_sdk::transaction_context::TransactionAccount;
+
+use solana_program_runtime::invoke_context::BuiltinFunctionWithContext;
+use solana_program_runtime::loaded_programs::ProgramCacheEntry;
+use solana_program_runtime::with_mock_invoke_context;
+
+/// Program the tests execute as.
+pub(crate) const CALLER_PROGRAM_ID: Pubkey = Pubkey::new_from_array([1; 32]);
+
+/// Returns an executable account of a builtin program.
+pub(crate) fn builtin_program_account() -> AccountSharedData {
+    let mut account = AccountSharedData::new(1, 0, &native_loader::id());
+    account.set_executable(true);
+    account
+}
+
+/// Runs `f` as if `CALLER_PROGRAM_ID` was executing with `accounts` as its writable signer
+/// accounts. `builtins` are registered under their program id, which CPIs resolve through
+/// the program account's owner unless the account is owned by the native loader.
+pub(crate) fn with_mock_caller<R>(
+    accounts: Vec<TransactionAccount>,
+    builtins: &[(Pubkey, BuiltinFunctionWithContext)],
+    f: impl FnOnce() -> R,
+) -> R {
+    let instruction_accounts = (0..accounts.len() as IndexOfAccount)
+        .map(|index| InstructionAccount {
+            index_in_transaction: index,
+            index_in_caller: index,
+            index_in_callee: index,
+            is_signer: true,
+            is_writable: true,
+        })
+        .collect::<Vec<_>>();
+    let mut transaction_accounts = accounts;
+    for (program_id, _) in builtins {
+        if !transaction_accounts
+            .iter()
+            .any(|(key, _)| key == program_id)
+        {
+            transaction_accounts.push((*program_id, builtin_program_account()));
+        }
+    }
+    let caller_index = transaction_accounts.len() as IndexOfAccount;
+    transaction_accounts.push((CALLER_PROGRAM_ID, builtin_program_account()));
+
+    with_mock_invoke_context!(invoke_context, transaction_context, transaction_accounts);
+    let mut program_cache_for_tx_batch = ProgramCacheForTxBatch::default();
+    for (program_id, builtin) in builtins {
+        program_cache_for_tx_batch.replenish(
+            *program_id,
+            Arc::new(ProgramCacheEntry::new_builtin(0, 0, *builtin)),
+        );
+    }
+    invoke_context.program_cache_for_tx_batch = &mut program_cache_for_tx_batch;
+    invoke_context
+        .transaction_context
+        .get_next_instruction_context()
+        .unwrap()
+        .configure(&[caller_index], &instruction_accounts, &[]);
+    invoke_context.push().unwrap();
+
+    with_invoke_context(&mut invoke_context, f)
+}
+
+/// Serializes the current instruction's accounts in the input layout of the BPF loader's
+/// aligned entrypoint, so `AccountInfo::realloc` finds the original data length and the
+/// spare capacity it relies on.
+pub(crate) fn serialize_instruction_accounts() -> Vec<u64> {
+    let transaction_context = &get_invoke_context().transaction_context;
+    let instruction_context = transaction_context
+        .get_current_instruction_context()
+        .unwrap();
+    let num_accounts = instruction_context.get_number_of_instruction_accounts();
+
+    let mut input = Vec::new();
+    input.extend_from_slice(&(num_accounts as u64).to_le_bytes());
+    for index in 0..num_accounts {
+        if let Some(duplicate_of) = instruction_context
+            .is_instruction_account_duplicate(index)
+            .unwrap()
+        {
+            input.push(duplicate_of as u8);
+            input.extend_from_slice(&[0; 7]);
+            continue;
+        }
+        let account = instruction_context
+            .try_borrow_instruction_account(transaction_context, index)
+            .unwrap();
+        input.push(NON_DUP_MARKER);
+        input.push(account.is_signer() as u8);
+        input.push(account.is_writable() as u8);
+        input.push(account.is_executable() as u8);
+        input.extend_from_slice(&[0; 4]);
+        input.extend_from_slice(account.get_key().as_ref());
+        input.extend_from_slice(account.get_owner().as_ref());
+        input.extend_from_slice(&account.get_lamports().to_le_bytes());
+        input.extend_from_slice(&(account.get_data().len() as u64).to_le_bytes());
+        input.extend_from_slice(account.get_data());
+        input.resize(input.len() + MAX_PERMITTED_DATA_INCREASE, 0);
+        input.resize(input.len().next_multiple_of(BPF_ALIGN_OF_U128), 0);
+        input.extend_from_slice(&account.get_rent_epoch().to_le_bytes());
+    }
+    input.extend_from_slice(&0u64.to_le_bytes());
+    input.extend_from_slice(CALLER_PROGRAM_ID.as_ref());
+    input.resize(input.len().next_multiple_of(8), 0);
+
+    input
+        .chunks_exact(8)
+        .map(|chunk| u64::from_le_bytes(chunk.try_into().unwrap()))
+        .collect()
+}
+
+/// Deserializes the AccountInfos from `input` like the program entrypoint.
+pub(crate) fn account_infos(input: &mut [u64]) -> Vec<AccountInfo<'_>> {
+    unsafe { deserialize(input.as_mut_ptr() as *mut u8).1 }
+}
+
+/// Returns the account with `pubkey` in the current transaction.
+pub(crate) fn transaction_account(pubkey: &Pubkey) -> AccountSharedData {
+    let transaction_context = &get_invoke_context().transaction_context;
+    let index = transaction_context.find_index_of_account(pubkey).unwrap();
+    transaction_context
+        .get_account_at_index(index)
+        .unwrap()
+        .borrow()
+        .clone()
+}